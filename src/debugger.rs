@@ -0,0 +1,317 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashMap};
+use std::error::Error;
+use std::io::{self, BufRead, Write};
+use crate::AssembledProgram;
+use crate::parser::{ParserData, SymbolKind};
+use crate::parser::command::OPCODES;
+use crate::simulator::Simulator;
+
+// a call observed while stepping: where JSUB was, where it went and where it returns to
+struct Frame {
+    call: u32,
+    target: u32,
+    ret: u32
+}
+
+struct Watchpoint {
+    address: u32,
+    len: usize,
+    value: u64
+}
+
+pub struct Debugger<'a> {
+    data: &'a ParserData,
+    simulator: Simulator,
+    max_steps: usize,
+    breakpoints: BTreeSet<u32>,
+    watchpoints: Vec<Watchpoint>,
+    frames: Vec<Frame>,
+    // address -> index into data.listing of the statement assembled there
+    statements: HashMap<u32, usize>
+}
+
+impl<'a> Debugger<'a> {
+    pub fn new(program: &'a AssembledProgram, simulator: Simulator, max_steps: usize) -> Self {
+        let data = &program.data;
+        let mut statements = HashMap::new();
+
+        for (idx, line) in data.listing.iter().enumerate() {
+            if line.expression.is_some() && !line.opcode.is_empty() {
+                statements.entry(line.address as u32).or_insert(idx);
+            }
+        }
+
+        Self{data, simulator, max_steps, breakpoints: BTreeSet::new(), watchpoints: vec![], frames: vec![], statements}
+    }
+
+    // a label, a source line number or a hex address written as 0x1000
+    fn resolve(self: &Self, location: &str) -> Result<u32, Box<dyn Error>> {
+        if let Some(hex) = location.strip_prefix("0x") {
+            return Ok(u32::from_str_radix(hex, 16)?);
+        }
+
+        if let Ok(number) = location.parse::<usize>() {
+            let line = self.data.listing.iter().find(|line| {
+                !line.opcode.is_empty() && self.data.source[line.source].number == number
+            });
+            return match line {
+                Some(line) => Ok(line.address as u32),
+                None => Err(format!("Line {} has no code", number))?
+            };
+        }
+
+        for section in &self.data.sections {
+            if let Some(symbol) = section.symbol_table.get(location) {
+                return Ok(symbol.value as u32);
+            }
+        }
+
+        Err(format!("Symbol {} not found", location))?
+    }
+
+    // the label of an address, or the closest one before it; constants and literals are not places
+    fn describe(self: &Self, address: u32) -> String {
+        let mut best: Option<(&String, u32)> = None;
+
+        for section in &self.data.sections {
+            let header = &section.header;
+            if (address as u64) < header.start_address || header.start_address + header.len <= address as u64 {
+                continue;
+            }
+
+            for (name, symbol) in &section.symbol_table {
+                let value = symbol.value as u32;
+                // the symbol table is a HashMap, labels of the same address are picked by name
+                let closer = best.map(|(best_name, best)| (value, Reverse(name)) > (best, Reverse(best_name))).unwrap_or(true);
+                if symbol.kind == SymbolKind::Relative && value <= address && !name.starts_with('=') && closer {
+                    best = Some((name, value));
+                }
+            }
+        }
+
+        match best {
+            Some((name, value)) if value == address => format!("{:06X} <{}>", address, name),
+            Some((name, value)) => format!("{:06X} <{}+{}>", address, name, address - value),
+            None => format!("{:06X}", address)
+        }
+    }
+
+    fn print_location(self: &Self) {
+        let pc = self.simulator.registers.pc;
+
+        match self.statements.get(&pc) {
+            Some(&idx) => {
+                let line = &self.data.listing[idx];
+                let source = &self.data.source[line.source];
+                println!("{} line {}: {}", self.describe(pc), source.number, line.expression.as_ref().unwrap());
+            }
+            None => {
+                println!("{}", self.describe(pc));
+            }
+        }
+    }
+
+    // executes one instruction and keeps the call frames up to date
+    fn step(self: &mut Self) -> Result<bool, Box<dyn Error>> {
+        let pc = self.simulator.registers.pc;
+        let is_call = self.simulator.read(pc, 1)
+            .map(|byte| OPCODES.get(&(byte as u8 & 0xFC)).map(|(name, _)| *name == "JSUB").unwrap_or(false))
+            .unwrap_or(false);
+
+        let running = self.simulator.step()?;
+        let next = self.simulator.registers.pc;
+
+        if is_call {
+            // JSUB leaves the return address in L
+            self.frames.push(Frame{call: pc, target: next, ret: self.simulator.registers.l});
+        } else if let Some(depth) = self.frames.iter().rposition(|frame| frame.ret == next) {
+            // RSUB or J @RETADR back into a caller
+            self.frames.truncate(depth);
+        }
+
+        Ok(running)
+    }
+
+    // returns the reason execution stopped
+    fn resume(self: &mut Self, count: Option<usize>) -> Result<String, Box<dyn Error>> {
+        let limit = count.unwrap_or(self.max_steps);
+
+        for idx in 0..limit {
+            if self.simulator.halted {
+                return Ok(String::from("Program has finished"));
+            }
+
+            let pc = self.simulator.registers.pc;
+            if idx > 0 && count.is_none() && self.breakpoints.contains(&pc) {
+                return Ok(format!("Breakpoint at {}", self.describe(pc)));
+            }
+
+            if !self.step()? {
+                return Ok(String::from("Program has finished"));
+            }
+
+            for watchpoint in &mut self.watchpoints {
+                let value = self.simulator.read(watchpoint.address, watchpoint.len)?;
+                if value != watchpoint.value {
+                    let message = format!("Watchpoint {:06X}: {:0width$X} -> {:0width$X}", watchpoint.address, watchpoint.value, value, width = watchpoint.len * 2);
+                    watchpoint.value = value;
+                    return Ok(message);
+                }
+            }
+        }
+
+        match count {
+            Some(_) => Ok(String::new()),
+            None => Ok(format!("Still running after {} steps", limit))
+        }
+    }
+
+    fn execute(self: &mut Self, command: &str, args: &[&str]) -> Result<bool, Box<dyn Error>> {
+        match command {
+            "break" | "b" => {
+                let address = self.resolve(args.first().ok_or("break needs a label, line or address")?)?;
+                self.breakpoints.insert(address);
+                println!("Breakpoint at {}", self.describe(address));
+            }
+            "delete" | "d" => {
+                let address = self.resolve(args.first().ok_or("delete needs a label, line or address")?)?;
+                if !self.breakpoints.remove(&address) {
+                    Err(format!("No breakpoint at {:06X}", address))?
+                }
+            }
+            "watch" | "w" => {
+                let address = self.resolve(args.first().ok_or("watch needs a label or address")?)?;
+                let len = match args.get(1) {
+                    Some(len) => len.parse()?,
+                    None => 3
+                };
+                let value = self.simulator.read(address, len)?;
+                self.watchpoints.push(Watchpoint{address, len, value});
+                println!("Watching {} bytes at {}", len, self.describe(address));
+            }
+            "info" | "i" => {
+                for address in &self.breakpoints {
+                    println!("breakpoint {}", self.describe(*address));
+                }
+                for watchpoint in &self.watchpoints {
+                    println!("watchpoint {} ({} bytes)", self.describe(watchpoint.address), watchpoint.len);
+                }
+            }
+            "step" | "s" => {
+                let count = match args.first() {
+                    Some(count) => count.parse()?,
+                    None => 1
+                };
+                let reason = self.resume(Some(count))?;
+                if !reason.is_empty() {
+                    println!("{}", reason);
+                }
+                self.print_location();
+            }
+            "continue" | "c" => {
+                println!("{}", self.resume(None)?);
+                self.print_location();
+            }
+            "registers" | "regs" | "r" => {
+                println!("{}", self.simulator.registers);
+                println!("CC {:?}", self.simulator.condition_code());
+            }
+            "x" | "memory" => {
+                let address = self.resolve(args.first().ok_or("x needs a label or address")?)?;
+                let len: u32 = match args.get(1) {
+                    Some(len) => len.parse()?,
+                    None => 16
+                };
+                for row in (0..len).step_by(16) {
+                    let bytes: Vec<String> = (row..(row + 16).min(len))
+                        .map(|offset| self.simulator.read(address + offset, 1).map(|byte| format!("{:02X}", byte)))
+                        .collect::<Result<_, _>>()?;
+                    println!("{:06X}: {}", address + row, bytes.join(" "));
+                }
+            }
+            "backtrace" | "bt" => {
+                println!("#0 {}", self.describe(self.simulator.registers.pc));
+                for (depth, frame) in self.frames.iter().rev().enumerate() {
+                    println!("#{} {} called {} from {}", depth + 1, self.describe(frame.ret), self.describe(frame.target), self.describe(frame.call));
+                }
+            }
+            "where" | "list" | "l" => {
+                self.print_location();
+            }
+            "quit" | "q" => {
+                return Ok(false);
+            }
+            "help" | "h" => {
+                println!("break <label|line|0xaddr>   stop before the instruction there");
+                println!("delete <label|line|0xaddr>  remove a breakpoint");
+                println!("watch <label|0xaddr> [len]  stop when memory changes");
+                println!("info                        list breakpoints and watchpoints");
+                println!("step [n]                    execute n instructions");
+                println!("continue                    run until something stops the program");
+                println!("registers                   show the registers");
+                println!("x <label|0xaddr> [len]      dump memory");
+                println!("backtrace                   show the subroutine calls");
+                println!("where                       show the current statement");
+                println!("quit");
+            }
+            _ => {
+                Err(format!("Unknown command {}, try help", command))?
+            }
+        }
+
+        Ok(true)
+    }
+
+    pub fn run(self: &mut Self, input: impl BufRead) {
+        self.print_location();
+        print!("(sdb) ");
+        io::stdout().flush().ok();
+
+        for line in input.lines().map_while(Result::ok) {
+            let words: Vec<&str> = line.split_whitespace().collect();
+
+            if let Some((command, args)) = words.split_first() {
+                match self.execute(command, args) {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        return;
+                    }
+                    Err(err) => {
+                        println!("{}", err);
+                    }
+                }
+            }
+
+            print!("(sdb) ");
+            io::stdout().flush().ok();
+        }
+        println!();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assemble, Options};
+
+    #[test]
+    fn labels_of_one_address_are_picked_by_name() {
+        let source = "PROG    START   0
+        JSUB    ZSUB
+        J       *
+ZSUB    EQU     *
+ASUB    EQU     *
+MSUB    RSUB
+        END     PROG
+";
+        // every assembly gets symbol tables with a different iteration order
+        for _ in 0..16 {
+            let program = assemble(source, &Options::default()).ok().unwrap();
+            let debugger = Debugger::new(&program, Simulator::new(), 0);
+
+            assert_eq!(debugger.describe(6), "000006 <ASUB>");
+            assert_eq!(debugger.describe(7), "000007 <ASUB+1>");
+        }
+    }
+}
@@ -0,0 +1,146 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use crate::macros::{LineKind, SourceLine, EXPANSION_NOTE};
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Severity {
+    Error,
+    Warning
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Severity::Error => {
+                write!(f, "error")
+            }
+            Severity::Warning => {
+                write!(f, "warning")
+            }
+        }
+    }
+}
+
+// the part of a source line a diagnostic points at, in characters; u32 keeps Diagnostic small enough to return by value
+#[derive(Copy, Clone, Debug)]
+pub struct Span {
+    pub column: u32,
+    pub len: u32
+}
+
+pub type Diagnostics = Vec<Diagnostic>;
+
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    // index into ParserData::source of the statement it is about
+    pub source: Option<usize>,
+    pub span: Option<Span>,
+    pub notes: Vec<String>,
+    // line number and text, filled in by locate once the source lines are known
+    pub line: Option<(usize, String)>
+}
+
+impl Diagnostic {
+    pub fn error(message: impl Into<String>) -> Self {
+        Self{severity: Severity::Error, message: message.into(), source: None, span: None, notes: vec![], line: None}
+    }
+
+    pub fn warning(message: impl Into<String>) -> Self {
+        Self{severity: Severity::Warning, ..Self::error(message)}
+    }
+
+    pub fn with_span(mut self: Self, column: usize, len: usize) -> Self {
+        self.span = Some(Span{column: column as u32, len: len as u32});
+        self
+    }
+
+    pub fn with_note(mut self: Self, note: impl Into<String>) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+
+    pub fn at(mut self: Self, source: usize) -> Self {
+        self.source = Some(source);
+        self
+    }
+
+    pub fn locate(mut self: Self, lines: &[SourceLine]) -> Self {
+        if let Some(line) = self.source.and_then(|source| lines.get(source)) {
+            self.line = Some((line.number, line.text.clone()));
+            if line.kind == LineKind::Expansion {
+                self.notes.push(String::from(EXPANSION_NOTE));
+            }
+        }
+        self
+    }
+
+    // for files that are not assembly source, such as object programs
+    pub fn on_line(mut self: Self, number: usize, text: &str) -> Self {
+        self.line = Some((number, String::from(text)));
+        self
+    }
+
+    // rustc style: the message, where it is, the line itself and a caret under the culprit
+    pub fn render(self: &Self, file: &str) -> String {
+        let mut res = format!("{}: {}\n", self.severity, self.message);
+
+        if let Some((number, text)) = &self.line {
+            let width = number.to_string().len();
+            let column = self.span.map(|span| span.column as usize).unwrap_or(0);
+
+            res += &format!("{:width$}--> {}:{}:{}\n", "", file, number, column + 1, width = width);
+            res += &format!("{:width$} |\n", "", width = width);
+            res += &format!("{} | {}\n", number, text.trim_end());
+
+            if let Some(span) = self.span.filter(|span| span.len > 0) {
+                // keep the tabs so that the caret lines up with the text above it
+                let indent: String = text.chars().take(span.column as usize).map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+                res += &format!("{:width$} | {}{}\n", "", indent, "^".repeat(span.len as usize), width = width);
+            }
+        }
+
+        for note in &self.notes {
+            res += &format!("  = note: {}\n", note);
+        }
+
+        res
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.line {
+            Some((number, _)) => {
+                write!(f, "{} on line {}", self.message, number)
+            }
+            None => {
+                write!(f, "{}", self.message)
+            }
+        }
+    }
+}
+
+impl Error for Diagnostic {}
+
+impl From<&str> for Diagnostic {
+    fn from(message: &str) -> Self {
+        Self::error(message)
+    }
+}
+
+impl From<String> for Diagnostic {
+    fn from(message: String) -> Self {
+        Self::error(message)
+    }
+}
+
+impl From<Box<dyn Error>> for Diagnostic {
+    fn from(err: Box<dyn Error>) -> Self {
+        match err.downcast::<Diagnostic>() {
+            Ok(diagnostic) => *diagnostic,
+            Err(err) => Self::error(err.to_string())
+        }
+    }
+}
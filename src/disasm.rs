@@ -0,0 +1,303 @@
+use std::collections::{BTreeSet, HashMap};
+use crate::object::ObjectProgram;
+use crate::parser::command::{Format, OPCODES};
+
+const REGISTERS: [&str; 10] = ["A", "X", "L", "B", "S", "T", "F", "", "PC", "SW"];
+
+enum Operand {
+    None,
+    Text(String),
+    // a memory address, printed through a label when there is one
+    Address{address: u64, prefix: &'static str, indexed: bool}
+}
+
+struct Instruction {
+    address: u64,
+    len: usize,
+    code: String,
+    mnemonic: String,
+    operand: Operand,
+    annotation: String
+}
+
+impl Instruction {
+    fn data(address: u64, byte: u8) -> Self {
+        Self{address, len: 1, code: format!("{:02X}", byte), mnemonic: String::from("BYTE"), operand: Operand::Text(format!("X'{:02X}'", byte)), annotation: String::from("data")}
+    }
+
+    fn decode(bytes: &[u8], address: u64, base: &mut Option<u64>) -> Self {
+        let (name, format) = match OPCODES.get(&(bytes[0] & 0xFC)) {
+            Some(&(name, format)) => (name, format),
+            None => return Self::data(address, bytes[0])
+        };
+
+        match format {
+            Format::ONE => {
+                if bytes[0] & 0x03 != 0 {
+                    return Self::data(address, bytes[0]);
+                }
+                Self{address, len: 1, code: format!("{:02X}", bytes[0]), mnemonic: String::from(name), operand: Operand::None, annotation: String::from("format 1")}
+            }
+            Format::TWO => {
+                if bytes.len() < 2 || bytes[0] & 0x03 != 0 {
+                    return Self::data(address, bytes[0]);
+                }
+                let (r1, r2) = ((bytes[1] >> 4) as usize, (bytes[1] & 0x0F) as usize);
+                let register = |r: usize| REGISTERS.get(r).filter(|name| !name.is_empty()).copied();
+                let operand = match (name, register(r1), register(r2)) {
+                    ("SVC", _, _) => {
+                        Some(format!("{}", r1))
+                    }
+                    ("CLEAR", Some(r1), _) | ("TIXR", Some(r1), _) => {
+                        Some(String::from(r1))
+                    }
+                    ("SHIFTL", Some(r1), _) | ("SHIFTR", Some(r1), _) => {
+                        Some(format!("{},{}", r1, r2 + 1))
+                    }
+                    (_, Some(r1), Some(r2)) => {
+                        Some(format!("{},{}", r1, r2))
+                    }
+                    _ => {
+                        None
+                    }
+                };
+                match operand {
+                    Some(operand) => {
+                        Self{address, len: 2, code: format!("{:02X}{:02X}", bytes[0], bytes[1]), mnemonic: String::from(name), operand: Operand::Text(operand), annotation: String::from("format 2")}
+                    }
+                    None => {
+                        Self::data(address, bytes[0])
+                    }
+                }
+            }
+            Format::ThreeAndFour => {
+                if bytes.len() < 3 {
+                    return Self::data(address, bytes[0]);
+                }
+                let (n, i) = ((bytes[0] >> 1) & 1, bytes[0] & 1);
+                let (x, b, p, e) = ((bytes[1] >> 7) & 1, (bytes[1] >> 6) & 1, (bytes[1] >> 5) & 1, (bytes[1] >> 4) & 1);
+
+                if n == 0 && i == 0 {
+                    // standard SIC: x bit and a 15-bit address
+                    let target = (((bytes[1] & 0x7F) as u64) << 8) | bytes[2] as u64;
+                    let code = format!("{:02X}{:02X}{:02X}", bytes[0], bytes[1], bytes[2]);
+                    let operand = if name == "RSUB" && target == 0 && x == 0 {
+                        Operand::None
+                    } else {
+                        Operand::Address{address: target, prefix: "", indexed: x == 1}
+                    };
+                    return Self{address, len: 3, code, mnemonic: String::from(name), operand, annotation: format!("SIC -> {:06X}", target)};
+                }
+
+                let len = if e == 1 { 4 } else { 3 };
+                if bytes.len() < len {
+                    return Self::data(address, bytes[0]);
+                }
+
+                let code: String = bytes[..len].iter().map(|byte| format!("{:02X}", byte)).collect();
+                let prefix = match (n, i) {
+                    (1, 0) => "@",
+                    (0, 1) => "#",
+                    _ => ""
+                };
+                let flags = format!("nixbpe={}{}{}{}{}{}", n, i, x, b, p, e);
+                let mnemonic = if e == 1 { format!("+{}", name) } else { String::from(name) };
+
+                let (target, how) = if e == 1 {
+                    ((((bytes[1] & 0x0F) as u64) << 16) | ((bytes[2] as u64) << 8) | bytes[3] as u64, "direct")
+                } else {
+                    let disp = (((bytes[1] & 0x0F) as u64) << 8) | bytes[2] as u64;
+                    match (b, p) {
+                        (0, 1) => {
+                            // 12-bit signed displacement from the next instruction
+                            let disp = ((disp as i64) << 52) >> 52;
+                            (((address + 3) as i64 + disp) as u64 & 0xFFFFF, "pc-relative")
+                        }
+                        (1, 0) => {
+                            match base {
+                                Some(base) => {
+                                    (*base + disp, "base-relative")
+                                }
+                                None => {
+                                    let annotation = format!("{} base-relative with unknown base, disp {:03X}", flags, disp);
+                                    return Self{address, len, code, mnemonic, operand: Operand::Text(format!("{}{}", prefix, disp)), annotation};
+                                }
+                            }
+                        }
+                        (0, 0) => {
+                            (disp, "direct")
+                        }
+                        _ => {
+                            return Self::data(address, bytes[0]);
+                        }
+                    }
+                };
+
+                // #value is a constant unless it was computed from the location of something
+                let operand = if name == "RSUB" && target == 0 && x == 0 && b == 0 && p == 0 {
+                    Operand::None
+                } else if prefix == "#" && how == "direct" {
+                    Operand::Text(format!("#{}", target))
+                } else {
+                    Operand::Address{address: target, prefix, indexed: x == 1}
+                };
+
+                if name == "LDB" && prefix == "#" {
+                    *base = Some(target);
+                }
+
+                Self{address, len, code, mnemonic, operand, annotation: format!("{} {} -> {:06X}", flags, how, target)}
+            }
+        }
+    }
+}
+
+fn disassemble_program(program: &ObjectProgram, first: bool) -> Vec<String> {
+    let header = &program.header;
+    let end = header.start_address + header.len;
+    let mut instructions = vec![];
+    let mut base = None;
+
+    let mut texts: Vec<_> = program.texts.iter().collect();
+    texts.sort_by_key(|text| text.address);
+
+    for text in texts {
+        let mut offset = 0;
+        while offset < text.bytes.len() {
+            let instruction = Instruction::decode(&text.bytes[offset..], text.address + offset as u64, &mut base);
+            offset += instruction.len;
+            instructions.push(instruction);
+        }
+    }
+
+    for modification in &program.modifications {
+        let instruction = instructions.iter_mut()
+            .find(|instruction| instruction.address <= modification.address && modification.address < instruction.address + instruction.len as u64);
+        if let Some(instruction) = instruction {
+            match &modification.symbol {
+                Some((sign, name)) => {
+                    instruction.annotation += &format!(" M{}{}", sign, name);
+                    // a lone external reference is all there is to the address
+                    if let Operand::Address{address: 0, prefix, indexed} = instruction.operand {
+                        let name = if *sign == '+' { name.clone() } else { format!("-{}", name) };
+                        instruction.operand = Operand::Text(format!("{}{}{}", prefix, name, if indexed { ",X" } else { "" }));
+                    }
+                }
+                None => {
+                    instruction.annotation += " relocated";
+                }
+            }
+        }
+    }
+
+    // every address inside the program that something points at gets a label
+    let mut labels: HashMap<u64, String> = HashMap::new();
+    let mut targets = BTreeSet::new();
+
+    for (name, address) in &program.defines {
+        labels.insert(*address, name.clone());
+        targets.insert(*address);
+    }
+
+    for instruction in &instructions {
+        if let Operand::Address{address, ..} = instruction.operand {
+            if header.start_address <= address && address < end {
+                targets.insert(address);
+            }
+        }
+    }
+
+    if let Some(start) = program.start_address {
+        targets.insert(start);
+    }
+
+    let starts: BTreeSet<u64> = instructions.iter().map(|instruction| instruction.address).collect();
+    let covered = |address: u64| instructions.iter().any(|instruction| instruction.address < address && address < instruction.address + instruction.len as u64);
+
+    for &address in &targets {
+        if !labels.contains_key(&address) && !covered(address) {
+            labels.insert(address, format!("L{:06X}", address));
+        }
+    }
+
+    let mut res = vec![];
+    let directive = if first { "START" } else { "CSECT" };
+    let operand = if first { format!("{:X}", header.start_address) } else { String::new() };
+    res.push(format!("{:<8} {:<8} {}", header.program_name, directive, operand));
+
+    if !program.defines.is_empty() {
+        let names: Vec<&str> = program.defines.iter().map(|(name, _)| name.as_str()).collect();
+        res.push(format!("{:<8} {:<8} {}", "", "EXTDEF", names.join(",")));
+    }
+
+    if !program.references.is_empty() {
+        res.push(format!("{:<8} {:<8} {}", "", "EXTREF", program.references.join(",")));
+    }
+
+    // areas no text record covers are reserved space, split wherever a label points into them
+    let mut address = header.start_address;
+    let mut idx = 0;
+
+    while address < end || idx < instructions.len() {
+        let next = instructions.get(idx).map(|instruction| instruction.address).unwrap_or(end);
+
+        if address < next {
+            let stop = labels.keys().copied().filter(|&label| address < label && label < next && !starts.contains(&label)).min().unwrap_or(next);
+            let label = labels.get(&address).map(String::as_str).unwrap_or("");
+            res.push(format!("{:<8} {:<8} {:<16} . {:06X}", label, "RESB", stop - address, address));
+            address = stop;
+            continue;
+        }
+
+        let instruction = &instructions[idx];
+        let label = labels.get(&instruction.address).map(String::as_str).unwrap_or("");
+        let operand = match &instruction.operand {
+            Operand::None => {
+                String::new()
+            }
+            Operand::Text(text) => {
+                text.clone()
+            }
+            Operand::Address{address, prefix, indexed} => {
+                let target = match labels.get(address) {
+                    Some(label) => label.clone(),
+                    None => format!("{}", address)
+                };
+                format!("{}{}{}", prefix, target, if *indexed { ",X" } else { "" })
+            }
+        };
+
+        res.push(format!("{:<8} {:<8} {:<16} . {:06X} {:<8} {}", label, instruction.mnemonic, operand, instruction.address, instruction.code, instruction.annotation));
+        address = address.max(instruction.address + instruction.len as u64);
+        idx += 1;
+    }
+
+    res
+}
+
+pub fn disassemble(programs: &[ObjectProgram]) -> Vec<String> {
+    let mut res = vec![];
+
+    let first = match programs.first() {
+        Some(program) => program,
+        None => {
+            // not even an END without a program
+            return res;
+        }
+    };
+
+    for (idx, program) in programs.iter().enumerate() {
+        res.extend(disassemble_program(program, idx == 0));
+    }
+
+    // only the first section names the entry point
+    let entry = first.start_address.map(|start| {
+        match first.defines.iter().find(|(_, address)| *address == start) {
+            Some((name, _)) => name.clone(),
+            None => format!("L{:06X}", start)
+        }
+    });
+
+    res.push(format!("{:<8} {:<8} {}", "", "END", entry.unwrap_or_default()));
+    res
+}
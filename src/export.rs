@@ -0,0 +1,200 @@
+use crate::loader::LoadedProgram;
+use crate::macros::LineKind;
+use crate::parser::{ParserData, SymbolKind};
+
+// bytes per data record
+const RECORD_LEN: usize = 16;
+
+// the data records of a program: the address and the bytes, split so that none runs over a 64 KB boundary
+fn records(program: &LoadedProgram) -> Vec<(u64, &[u8])> {
+    let mut res = vec![];
+
+    for (start, len) in program.runs() {
+        let mut offset = start;
+        while offset < start + len {
+            let address = program.address + offset as u64;
+            let room = (0x10000 - (address & 0xFFFF)) as usize;
+            let end = (start + len).min(offset + RECORD_LEN.min(room));
+            res.push((address, &program.bytes[offset..end]));
+            offset = end;
+        }
+    }
+
+    res
+}
+
+// Intel HEX checksums make all the bytes of a record add up to zero
+fn intel_record(kind: u8, address: u16, data: &[u8]) -> String {
+    let mut bytes = vec![data.len() as u8, (address >> 8) as u8, address as u8, kind];
+    bytes.extend_from_slice(data);
+    let sum = bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    bytes.push(sum.wrapping_neg());
+
+    format!(":{}", hex::encode_upper(bytes))
+}
+
+// S-record checksums are the complement of the sum of the count, address and data bytes
+fn s_record(kind: u8, address: u64, width: usize, data: &[u8]) -> String {
+    let mut bytes = vec![(width + data.len() + 1) as u8];
+    bytes.extend((0..width).rev().map(|idx| (address >> (8 * idx)) as u8));
+    bytes.extend_from_slice(data);
+    let sum = bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    bytes.push(!sum);
+
+    format!("S{}{}", kind, hex::encode_upper(bytes))
+}
+
+// data records, extended linear address records whenever the upper 16 bits change,
+// the entry point as a start linear address record and the end of file record
+pub fn intel_hex(program: &LoadedProgram) -> Vec<String> {
+    let mut res = vec![];
+    let mut upper = 0;
+
+    for (address, bytes) in records(program) {
+        if address >> 16 != upper {
+            upper = address >> 16;
+            res.push(intel_record(0x04, 0, &(upper as u16).to_be_bytes()));
+        }
+        res.push(intel_record(0x00, address as u16, bytes));
+    }
+
+    if let Some(entry) = program.entry {
+        res.push(intel_record(0x05, 0, &(entry as u32).to_be_bytes()));
+    }
+    res.push(intel_record(0x01, 0, &[]));
+
+    res
+}
+
+// S1/S2/S3 data records depending on how wide the addresses have to be, the S5 record count
+// and an S9/S8/S7 record holding the entry point
+pub fn s_records(program: &LoadedProgram) -> Vec<String> {
+    let records = records(program);
+    // the address of the last byte, not the one after it
+    let last = (program.address + program.bytes.len() as u64).saturating_sub(1);
+    let entry = program.entry.unwrap_or(program.address);
+    let (data, end, width) = match last.max(entry) {
+        0..=0xFFFF => (1, 9, 2),
+        0x10000..=0xFFFFFF => (2, 8, 3),
+        _ => (3, 7, 4)
+    };
+
+    let mut res = vec![s_record(0, 0, 2, program.name.as_bytes())];
+
+    for (address, bytes) in &records {
+        res.push(s_record(data, *address, width, bytes));
+    }
+
+    if records.len() <= 0xFFFF {
+        res.push(s_record(5, records.len() as u64, 2, &[]));
+    }
+    res.push(s_record(end, entry, width, &[]));
+
+    res
+}
+
+fn json_string(text: &str) -> String {
+    let mut res = String::from("\"");
+
+    for c in text.chars() {
+        match c {
+            '"' => res += "\\\"",
+            '\\' => res += "\\\\",
+            '\n' => res += "\\n",
+            '\t' => res += "\\t",
+            c if (c as u32) < 0x20 => res += &format!("\\u{:04x}", c as u32),
+            c => res.push(c)
+        }
+    }
+
+    res + "\""
+}
+
+fn json_list(items: Vec<String>, indent: &str) -> String {
+    if items.is_empty() {
+        return String::from("[]");
+    }
+
+    format!("[\n{indent}  {}\n{indent}]", items.join(&format!(",\n{indent}  ")), indent = indent)
+}
+
+// the sections with their records and symbols, and every listing line with the bytes generated for it;
+// addresses and lengths are plain numbers
+pub fn json(data: &ParserData) -> String {
+    let mut sections = vec![];
+
+    for (section, program) in data.sections.iter().zip(data.object_programs()) {
+        let header = &section.header;
+
+        let blocks = section.blocks.iter().map(|block| {
+            format!("{{\"name\": {}, \"address\": {}, \"length\": {}}}", json_string(&block.name), block.start_address, block.len)
+        }).collect();
+
+        let defines = program.defines.iter().map(|(name, address)| {
+            format!("{{\"name\": {}, \"address\": {}}}", json_string(name), address)
+        }).collect();
+
+        let references = program.references.iter().map(|name| json_string(name)).collect();
+
+        let texts = program.texts.iter().map(|text| {
+            format!("{{\"address\": {}, \"length\": {}, \"bytes\": \"{}\"}}", text.address, text.bytes.len(), hex::encode_upper(&text.bytes))
+        }).collect();
+
+        let modifications = program.modifications.iter().map(|modification| {
+            let symbol = match &modification.symbol {
+                Some((sign, name)) => json_string(&format!("{}{}", sign, name)),
+                None => String::from("null")
+            };
+            format!("{{\"address\": {}, \"half_bytes\": {}, \"symbol\": {}}}", modification.address, modification.len, symbol)
+        }).collect();
+
+        let mut names: Vec<&String> = section.symbol_table.keys().collect();
+        names.sort();
+        let symbols = names.into_iter().map(|name| {
+            let symbol = &section.symbol_table[name];
+            let (kind, block) = match symbol.kind {
+                SymbolKind::Absolute => ("absolute", String::from("null")),
+                SymbolKind::Relative => ("relative", json_string(&section.blocks[symbol.block].name)),
+                SymbolKind::External => ("external", String::from("null"))
+            };
+            format!("{{\"name\": {}, \"value\": {}, \"kind\": \"{}\", \"block\": {}}}", json_string(name), symbol.value, kind, block)
+        }).collect();
+
+        let entry = match program.start_address {
+            Some(entry) => entry.to_string(),
+            None => String::from("null")
+        };
+
+        sections.push(format!(
+            "{{\n      \"name\": {},\n      \"address\": {},\n      \"length\": {},\n      \"entry\": {},\n      \"blocks\": {},\n      \"defines\": {},\n      \"references\": {},\n      \"texts\": {},\n      \"modifications\": {},\n      \"symbols\": {}\n    }}",
+            json_string(&header.program_name), header.start_address, header.len, entry,
+            json_list(blocks, "      "), json_list(defines, "      "), json_list(references, "      "),
+            json_list(texts, "      "), json_list(modifications, "      "), json_list(symbols, "      ")
+        ));
+    }
+
+    let listing = data.listing.iter().map(|line| {
+        let source = &data.source[line.source];
+        let kind = match source.kind {
+            LineKind::Source => "source",
+            LineKind::Expansion => "expansion",
+            LineKind::Definition => "definition",
+            LineKind::Call => "call",
+            LineKind::Control => "control",
+            LineKind::Comment => "comment"
+        };
+
+        match &line.expression {
+            Some(expression) => {
+                format!("{{\"line\": {}, \"kind\": \"{}\", \"text\": {}, \"address\": {}, \"block\": {}, \"length\": {}, \"bytes\": \"{}\"}}",
+                        source.number, kind, json_string(&source.text), line.address, line.block, expression.len(), line.opcode)
+            }
+            None => {
+                format!("{{\"line\": {}, \"kind\": \"{}\", \"text\": {}, \"address\": null, \"block\": null, \"length\": 0, \"bytes\": \"\"}}",
+                        source.number, kind, json_string(&source.text))
+            }
+        }
+    }).collect();
+
+    format!("{{\n  \"sections\": {},\n  \"listing\": {}\n}}\n", json_list(sections, "  "), json_list(listing, "  "))
+}
@@ -1,9 +1,55 @@
+#[derive(Copy, Clone, Default, PartialEq)]
+pub enum SourceFormat {
+    // fields separated by blanks
+    #[default]
+    Free,
+    // label in columns 1-8, command in 10-15 and operand in 17-34, a + goes into column 9 and # or @ into 16
+    Fixed
+}
+
 pub fn parse_line_to_lexemes(line: &str) -> Vec<String> {
+    parse_line_to_spans(line).into_iter().map(|(_, lexeme)| lexeme).collect()
+}
+
+// a comment starts with a '.' in front of a lexeme, a line starting with one is all comment
+pub fn comment_column(line: &str) -> Option<usize> {
+    let mut in_str = false;
+    let mut in_lexeme = false;
+
+    for (column, c) in line.chars().enumerate() {
+        match c {
+            '.' if !in_str && !in_lexeme => {
+                return Some(column);
+            }
+            '\'' => {
+                in_str = !in_str;
+                in_lexeme = true;
+            }
+            ' ' | '\t' => {
+                in_lexeme = in_str;
+            }
+            _ => {
+                in_lexeme = true;
+            }
+        }
+    }
+
+    None
+}
+
+// the lexemes together with the column, in characters, where each of them starts; comments are left out
+pub fn parse_line_to_spans(line: &str) -> Vec<(usize, String)> {
     let mut lexeme = String::new();
+    let mut start = 0;
     let mut res= vec![];
     let mut in_str = false;
+    let end = comment_column(line).unwrap_or(usize::MAX);
+
+    for (column, c) in line.chars().enumerate().take_while(|(column, _)| *column < end) {
+        if lexeme.is_empty() {
+            start = column;
+        }
 
-    for c in line.chars() {
         match c {
             '\'' => {
                 lexeme.push(c);
@@ -12,8 +58,8 @@ pub fn parse_line_to_lexemes(line: &str) -> Vec<String> {
             ' ' | '\t' => {
                 if in_str {
                     lexeme.push(' ');
-                } else if lexeme.len() > 0 {
-                    res.push(lexeme);
+                } else if !lexeme.is_empty() {
+                    res.push((start, lexeme));
                     lexeme = String::new();
                 }
             }
@@ -24,11 +70,38 @@ pub fn parse_line_to_lexemes(line: &str) -> Vec<String> {
         }
     }
 
-    if lexeme.len() > 0 {
-        res.push(lexeme);
+    if !lexeme.is_empty() {
+        res.push((start, lexeme));
     }
 
     res
 }
 
+// the label, command and operand fields of a card image that are not blank, the index of the
+// command among them, and where the comment starts
+pub fn parse_fixed_line(line: &str) -> (Vec<(usize, String)>, Option<usize>, Option<usize>) {
+    let chars: Vec<char> = line.chars().collect();
+
+    if chars.first() == Some(&'.') || chars.iter().all(|c| c.is_whitespace()) {
+        return (vec![], None, Some(0).filter(|_| !chars.is_empty()));
+    }
+
+    let mut res = vec![];
+    let mut command = None;
+
+    for (idx, (start, end)) in [(0, 8), (8, 15), (15, 34)].into_iter().enumerate() {
+        let field: String = chars.iter().take(end).skip(start).collect();
+        let text = field.trim();
+
+        if !text.is_empty() {
+            if idx == 1 {
+                command = Some(res.len());
+            }
+            res.push((start + field.len() - field.trim_start().len(), String::from(text)));
+        }
+    }
+
+    let comment = chars.iter().skip(34).position(|c| !c.is_whitespace()).map(|offset| 34 + offset);
 
+    (res, command, comment)
+}
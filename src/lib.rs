@@ -0,0 +1,135 @@
+mod parser;
+mod lexer;
+mod macros;
+mod object;
+mod disasm;
+mod simulator;
+mod debugger;
+mod loader;
+mod diagnostic;
+mod export;
+
+pub use debugger::Debugger;
+pub use diagnostic::{Diagnostic, Diagnostics, Severity};
+pub use disasm::disassemble;
+pub use export::{intel_hex, s_records};
+pub use lexer::SourceFormat;
+pub use loader::LoadedProgram;
+pub use object::ObjectProgram;
+pub use parser::command::Mode;
+pub use parser::SymbolKind;
+pub use simulator::Simulator;
+
+use parser::ParserData;
+
+#[derive(Copy, Clone, Default)]
+pub struct Options {
+    pub mode: Mode,
+    pub format: SourceFormat,
+    // promote format 3 instructions to format 4 when their operand is out of reach
+    pub relax: bool
+}
+
+// a symbol of a control section; relative symbols name their program block, "" for the default one
+#[derive(Debug, PartialEq)]
+pub struct SymbolEntry<'a> {
+    pub section: &'a str,
+    pub name: &'a str,
+    pub value: u64,
+    pub kind: SymbolKind,
+    pub block: Option<&'a str>
+}
+
+// a line of the listing; statements have an address and a block, everything else only its text
+#[derive(Debug, PartialEq)]
+pub struct ListingEntry<'a> {
+    pub line: usize,
+    pub text: &'a str,
+    pub address: Option<u64>,
+    pub block: Option<usize>,
+    pub bytes: Vec<u8>
+}
+
+// an assembled source file: the listing, the symbol tables and the records of every control section
+pub struct AssembledProgram {
+    data: ParserData
+}
+
+impl AssembledProgram {
+    // the H/D/R/T/M/E records, as they are written to an object file
+    pub fn records(self: &Self) -> String {
+        self.data.object_program()
+    }
+
+    pub fn listing(self: &Self) -> String {
+        self.data.listing()
+    }
+
+    // the symbols of every control section sorted by name; literals are left out
+    pub fn symbols(self: &Self) -> Vec<SymbolEntry<'_>> {
+        let mut res = vec![];
+
+        for section in &self.data.sections {
+            let mut names: Vec<&String> = section.symbol_table.keys().filter(|name| !name.starts_with('=')).collect();
+            names.sort();
+
+            for name in names {
+                let symbol = &section.symbol_table[name];
+                let block = match symbol.kind {
+                    SymbolKind::Relative => Some(section.blocks[symbol.block].name.as_str()),
+                    SymbolKind::Absolute | SymbolKind::External => None
+                };
+                res.push(SymbolEntry{section: &section.header.program_name, name, value: symbol.value, kind: symbol.kind, block});
+            }
+        }
+
+        res
+    }
+
+    // every source line with the bytes generated for it
+    pub fn listing_lines(self: &Self) -> Vec<ListingEntry<'_>> {
+        self.data.listing.iter().map(|line| {
+            let source = &self.data.source[line.source];
+            match &line.expression {
+                Some(_) => {
+                    let bytes = hex::decode(&line.opcode).unwrap_or_default();
+                    ListingEntry{line: source.number, text: &source.text, address: Some(line.address), block: Some(line.block), bytes}
+                }
+                None => {
+                    ListingEntry{line: source.number, text: &source.text, address: None, block: None, bytes: vec![]}
+                }
+            }
+        }).collect()
+    }
+
+    // symbols() one per line, the way --symbols writes them
+    pub fn symbol_table(self: &Self) -> String {
+        let mut res = format!("{:<8} {:<8} {:>8} {:<8} {}\n", "section", "symbol", "value", "kind", "block");
+
+        for symbol in self.symbols() {
+            let (kind, block) = match (symbol.kind, symbol.block) {
+                (SymbolKind::Relative, Some("")) => ("rel", "(default)"),
+                (SymbolKind::Relative, block) => ("rel", block.unwrap_or_default()),
+                (SymbolKind::Absolute, _) => ("abs", ""),
+                (SymbolKind::External, _) => ("ext", "")
+            };
+            let line = format!("{:<8} {:<8} {:>8X} {:<8} {}", symbol.section, symbol.name, symbol.value, kind, block);
+            res += &format!("{}\n", line.trim_end());
+        }
+
+        res
+    }
+
+    pub fn json(self: &Self) -> String {
+        export::json(&self.data)
+    }
+
+    // the records as the loader sees them, ready for LoadedProgram::link
+    pub fn object_programs(self: &Self) -> Vec<ObjectProgram> {
+        self.data.object_programs()
+    }
+}
+
+pub fn assemble(source: &str, options: &Options) -> Result<AssembledProgram, Diagnostics> {
+    ParserData::from_source(source, *options).map(|data| AssembledProgram{data})
+}
@@ -0,0 +1,213 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use crate::object::ObjectProgram;
+use crate::parser::HeaderSection;
+use crate::simulator::MEMORY_SIZE;
+
+// one line of the external symbol table: a control section, or a symbol it defines
+pub struct EstabEntry {
+    pub section: String,
+    pub symbol: Option<String>,
+    pub address: u64,
+    pub len: u64
+}
+
+// several control sections linked together and relocated to one load address
+pub struct LoadedProgram {
+    pub name: String,
+    pub address: u64,
+    pub bytes: Vec<u8>,
+    // which bytes a text record wrote, the rest is reserved space
+    pub loaded: Vec<bool>,
+    pub entry: Option<u64>,
+    pub estab: Vec<EstabEntry>
+}
+
+impl LoadedProgram {
+    // pass 1: give every control section its address and collect the external symbols
+    fn build_estab(programs: &[ObjectProgram], address: u64) -> Result<Vec<EstabEntry>, Box<dyn Error>> {
+        let mut estab = vec![];
+        let mut names = HashSet::new();
+        let mut csaddr = address;
+
+        for program in programs {
+            let header = &program.header;
+
+            if !names.insert(header.program_name.clone()) {
+                Err(format!("Duplicate external symbol {}", header.program_name))?
+            }
+            estab.push(EstabEntry{section: header.program_name.clone(), symbol: None, address: csaddr, len: header.len});
+
+            for (name, value) in &program.defines {
+                if *value < header.start_address {
+                    Err(format!("Symbol {} lies before the start of {}", name, header.program_name))?
+                }
+                let value = csaddr + value - header.start_address;
+                if !names.insert(name.clone()) {
+                    Err(format!("Duplicate external symbol {}", name))?
+                }
+                estab.push(EstabEntry{section: header.program_name.clone(), symbol: Some(name.clone()), address: value, len: 0});
+            }
+
+            csaddr += header.len;
+        }
+
+        if csaddr > MEMORY_SIZE as u64 {
+            Err(format!("Program ends at {:X}, past the end of memory", csaddr))?
+        }
+
+        Ok(estab)
+    }
+
+    pub fn link(programs: &[ObjectProgram], address: u64) -> Result<Self, Box<dyn Error>> {
+        let estab = Self::build_estab(programs, address)?;
+        let symbols: HashMap<&String, u64> = estab.iter()
+            .map(|entry| (entry.symbol.as_ref().unwrap_or(&entry.section), entry.address))
+            .collect();
+        let len: u64 = programs.iter().map(|program| program.header.len).sum();
+        let mut bytes = vec![0; len as usize];
+        let mut loaded = vec![false; len as usize];
+        let mut entry = None;
+
+        // pass 2: load the text records and apply the modifications
+        for (program, section) in programs.iter().zip(estab.iter().filter(|entry| entry.symbol.is_none())) {
+            let header = &program.header;
+            let offset = section.address - address;
+
+            for text in &program.texts {
+                if text.address < header.start_address || text.address + text.bytes.len() as u64 > header.start_address + header.len {
+                    Err(format!("Text record at {:06X} lies outside of {}", text.address, header.program_name))?
+                }
+                let start = (offset + text.address - header.start_address) as usize;
+                bytes[start..start + text.bytes.len()].copy_from_slice(&text.bytes);
+                loaded[start..start + text.bytes.len()].iter_mut().for_each(|byte| *byte = true);
+            }
+
+            for modification in &program.modifications {
+                let delta = match &modification.symbol {
+                    Some((sign, name)) => {
+                        let value = match symbols.get(name) {
+                            Some(value) => *value as i64,
+                            None => Err(format!("Undefined external symbol {} in {}", name, header.program_name))?
+                        };
+                        if *sign == '-' { -value } else { value }
+                    }
+                    None => {
+                        // relative to the section itself
+                        section.address as i64 - header.start_address as i64
+                    }
+                };
+
+                // an odd number of half-bytes leaves the first half-byte alone
+                let size = (modification.len as usize).div_ceil(2);
+                if modification.len == 0 || size > 8 || modification.address < header.start_address || modification.address + size as u64 > header.start_address + header.len {
+                    Err(format!("Modification record at {:06X} lies outside of {}", modification.address, header.program_name))?
+                }
+                let start = (offset + modification.address - header.start_address) as usize;
+                let field = &mut bytes[start..start + size];
+
+                let value = field.iter().fold(0u64, |value, byte| (value << 8) | *byte as u64);
+                let mask = if modification.len >= 16 { u64::MAX } else { (1 << (modification.len * 4)) - 1 };
+                let value = (value & !mask) | ((value as i64 + delta) as u64 & mask);
+
+                for (idx, byte) in field.iter_mut().enumerate() {
+                    *byte = (value >> (8 * (size - 1 - idx))) as u8;
+                }
+            }
+
+            // the first section that names an entry point is where execution begins
+            if entry.is_none() {
+                if let Some(start) = program.start_address {
+                    entry = Some(section.address + start - header.start_address);
+                }
+            }
+        }
+
+        let name = match programs.first() {
+            Some(program) => program.header.program_name.clone(),
+            None => Err("Nothing to link")?
+        };
+
+        Ok(Self{name, address, bytes, loaded, entry, estab})
+    }
+
+    pub fn estab_lines(self: &Self) -> Vec<String> {
+        let mut res = vec![format!("{:<8} {:<8} {:>8} {:>8}", "section", "symbol", "address", "length")];
+
+        for entry in &self.estab {
+            match &entry.symbol {
+                Some(symbol) => {
+                    res.push(format!("{:<8} {:<8} {:>8X}", "", symbol, entry.address));
+                }
+                None => {
+                    res.push(format!("{:<8} {:<8} {:>8X} {:>8X}", entry.section, "", entry.address, entry.len));
+                }
+            }
+        }
+
+        res
+    }
+
+    // sixteen bytes a line, bytes no text record wrote are shown as ..
+    pub fn memory_lines(self: &Self) -> Vec<String> {
+        let mut res = vec![];
+
+        for (row, chunk) in self.bytes.chunks(16).enumerate() {
+            let mut line = format!("{:06X}", self.address + row as u64 * 16);
+            for (idx, byte) in chunk.iter().enumerate() {
+                if idx % 4 == 0 {
+                    line += " ";
+                }
+                if self.loaded[row * 16 + idx] {
+                    line += &format!("{:02X}", byte);
+                } else {
+                    line += "..";
+                }
+            }
+            res.push(line);
+        }
+
+        res
+    }
+
+    // the stretches of bytes text records wrote, as offsets from address and lengths
+    pub fn runs(self: &Self) -> Vec<(usize, usize)> {
+        let mut res = vec![];
+        let mut idx = 0;
+
+        while idx < self.bytes.len() {
+            if !self.loaded[idx] {
+                idx += 1;
+                continue;
+            }
+
+            let start = idx;
+            while idx < self.bytes.len() && self.loaded[idx] {
+                idx += 1;
+            }
+            res.push((start, idx - start));
+        }
+
+        res
+    }
+
+    // an absolute object program needs no modification records to be loaded
+    pub fn object_lines(self: &Self) -> Vec<String> {
+        let header = HeaderSection{program_name: self.name.clone(), start_address: self.address, len: self.bytes.len() as u64};
+        let mut res = vec![format!("{}", header)];
+
+        for (start, len) in self.runs() {
+            for offset in (0..len).step_by(30) {
+                let bytes = &self.bytes[start + offset..start + len.min(offset + 30)];
+                res.push(format!("T{:06X}{:02X}{}", self.address + (start + offset) as u64, bytes.len(), hex::encode_upper(bytes)));
+            }
+        }
+
+        match self.entry {
+            Some(entry) => res.push(format!("E{:06X}", entry)),
+            None => res.push(String::from("E"))
+        }
+
+        res
+    }
+}
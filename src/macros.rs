@@ -0,0 +1,992 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use crate::diagnostic::{Diagnostic, Diagnostics};
+use crate::lexer;
+use crate::parser::command::COMMANDS;
+
+// expansions nested deeper than this are assumed to be recursive
+const MAX_DEPTH: usize = 64;
+// a WHILE running longer than this is assumed to never end
+const MAX_ITERATIONS: usize = 65536;
+
+pub const EXPANSION_NOTE: &str = "the line comes from the expansion of the macro called here";
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum LineKind {
+    Source,
+    Definition,
+    Call,
+    Expansion,
+    // SET, IF, ELSE, ENDIF, WHILE, ENDW and the SIC mode line
+    Control,
+    // blank lines and lines holding nothing but a comment
+    Comment
+}
+
+#[derive(Clone)]
+pub struct SourceLine {
+    // line number in the source file, expansions share the number of their call
+    pub number: usize,
+    pub text: String,
+    pub kind: LineKind
+}
+
+pub struct Macro {
+    pub name: String,
+    // parameter names without the leading '&', with their default values
+    pub parameters: Vec<(String, Option<String>)>,
+    pub body: Vec<String>
+}
+
+#[derive(Clone, PartialEq)]
+enum Value {
+    Integer(i64),
+    Text(String)
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Integer(num) => {
+                write!(f, "{}", num)
+            }
+            Value::Text(text) => {
+                write!(f, "{}", text)
+            }
+        }
+    }
+}
+
+impl Value {
+    fn is_true(self: &Self) -> bool {
+        match self {
+            Value::Integer(num) => {
+                *num != 0
+            }
+            Value::Text(text) => {
+                !text.is_empty()
+            }
+        }
+    }
+
+    fn integer(self: &Self) -> Result<i64, Box<dyn Error>> {
+        match self {
+            Value::Integer(num) => {
+                Ok(*num)
+            }
+            Value::Text(text) => {
+                Err(format!("{} is not a number", text))?
+            }
+        }
+    }
+
+    fn parse(s: &str) -> Result<Self, Box<dyn Error>> {
+        let chars: Vec<char> = s.chars().collect();
+        let mut pos = 0;
+        let value = Self::parse_or(&chars, &mut pos)?;
+
+        Self::skip_spaces(&chars, &mut pos);
+        if pos != chars.len() {
+            Err(format!("Invalid condition {}", s.trim()))?
+        }
+
+        Ok(value)
+    }
+
+    fn skip_spaces(chars: &[char], pos: &mut usize) {
+        while *pos < chars.len() && chars[*pos].is_whitespace() {
+            *pos += 1;
+        }
+    }
+
+    fn peek_word(chars: &[char], pos: &mut usize) -> String {
+        Self::skip_spaces(chars, pos);
+        chars[*pos..].iter().take_while(|c| c.is_alphanumeric() || **c == '_').collect()
+    }
+
+    // consumes the keyword if it is the next word
+    fn eat_keyword(chars: &[char], pos: &mut usize, keyword: &str) -> bool {
+        if Self::peek_word(chars, pos) == keyword {
+            *pos += keyword.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    // or := and ('OR' and)*
+    fn parse_or(chars: &[char], pos: &mut usize) -> Result<Self, Box<dyn Error>> {
+        let mut value = Self::parse_and(chars, pos)?;
+
+        while Self::eat_keyword(chars, pos, "OR") {
+            let rhs = Self::parse_and(chars, pos)?;
+            value = Value::Integer((value.is_true() || rhs.is_true()) as i64);
+        }
+
+        Ok(value)
+    }
+
+    // and := not ('AND' not)*
+    fn parse_and(chars: &[char], pos: &mut usize) -> Result<Self, Box<dyn Error>> {
+        let mut value = Self::parse_not(chars, pos)?;
+
+        while Self::eat_keyword(chars, pos, "AND") {
+            let rhs = Self::parse_not(chars, pos)?;
+            value = Value::Integer((value.is_true() && rhs.is_true()) as i64);
+        }
+
+        Ok(value)
+    }
+
+    // not := 'NOT' not | relation
+    fn parse_not(chars: &[char], pos: &mut usize) -> Result<Self, Box<dyn Error>> {
+        if Self::eat_keyword(chars, pos, "NOT") {
+            let value = Self::parse_not(chars, pos)?;
+            return Ok(Value::Integer(!value.is_true() as i64));
+        }
+
+        Self::parse_relation(chars, pos)
+    }
+
+    // relation := sum (('EQ' | 'NE' | 'LT' | 'LE' | 'GT' | 'GE') sum)?
+    fn parse_relation(chars: &[char], pos: &mut usize) -> Result<Self, Box<dyn Error>> {
+        let lhs = Self::parse_sum(chars, pos)?;
+        let operator = Self::peek_word(chars, pos);
+
+        if !["EQ", "NE", "LT", "LE", "GT", "GE"].contains(&operator.as_str()) {
+            return Ok(lhs);
+        }
+
+        *pos += operator.len();
+        let rhs = Self::parse_sum(chars, pos)?;
+
+        let ordering = match (&lhs, &rhs) {
+            (Value::Integer(lhs), Value::Integer(rhs)) => {
+                lhs.cmp(rhs)
+            }
+            _ => {
+                lhs.to_string().cmp(&rhs.to_string())
+            }
+        };
+
+        let res = match operator.as_str() {
+            "EQ" => ordering.is_eq(),
+            "NE" => ordering.is_ne(),
+            "LT" => ordering.is_lt(),
+            "LE" => ordering.is_le(),
+            "GT" => ordering.is_gt(),
+            _ => ordering.is_ge()
+        };
+
+        Ok(Value::Integer(res as i64))
+    }
+
+    // sum := product (('+' | '-') product)*
+    fn parse_sum(chars: &[char], pos: &mut usize) -> Result<Self, Box<dyn Error>> {
+        let mut value = Self::parse_product(chars, pos)?;
+
+        loop {
+            Self::skip_spaces(chars, pos);
+            let operator = match chars.get(*pos) {
+                Some('+') | Some('-') => {
+                    chars[*pos]
+                }
+                _ => {
+                    break;
+                }
+            };
+            *pos += 1;
+            let lhs = value.integer()?;
+            let rhs = Self::parse_product(chars, pos)?.integer()?;
+            let res = if operator == '+' { lhs.checked_add(rhs) } else { lhs.checked_sub(rhs) };
+            value = Value::Integer(res.ok_or("Arithmetic overflow in condition")?);
+        }
+
+        Ok(value)
+    }
+
+    // product := factor (('*' | '/') factor)*
+    fn parse_product(chars: &[char], pos: &mut usize) -> Result<Self, Box<dyn Error>> {
+        let mut value = Self::parse_factor(chars, pos)?;
+
+        loop {
+            Self::skip_spaces(chars, pos);
+            let operator = match chars.get(*pos) {
+                Some('*') | Some('/') => {
+                    chars[*pos]
+                }
+                _ => {
+                    break;
+                }
+            };
+            *pos += 1;
+            let lhs = value.integer()?;
+            let rhs = Self::parse_factor(chars, pos)?.integer()?;
+            let res = if operator == '*' {
+                lhs.checked_mul(rhs)
+            } else if rhs == 0 {
+                Err("Division by zero in condition")?
+            } else {
+                lhs.checked_div(rhs)
+            };
+            value = Value::Integer(res.ok_or("Arithmetic overflow in condition")?);
+        }
+
+        Ok(value)
+    }
+
+    // factor := '-' factor | '(' or ')' | integer | X'hex' | 'text' | word
+    fn parse_factor(chars: &[char], pos: &mut usize) -> Result<Self, Box<dyn Error>> {
+        let word = Self::peek_word(chars, pos);
+
+        // an argument left empty leaves nothing in front of the operator
+        if ["EQ", "NE", "LT", "LE", "GT", "GE", "AND", "OR"].contains(&word.as_str()) {
+            return Ok(Value::Text(String::new()));
+        }
+
+        match chars.get(*pos) {
+            None | Some(')') => {
+                Ok(Value::Text(String::new()))
+            }
+            Some('-') => {
+                *pos += 1;
+                let value = Self::parse_factor(chars, pos)?.integer()?;
+                Ok(Value::Integer(value.checked_neg().ok_or("Arithmetic overflow in condition")?))
+            }
+            Some('(') => {
+                *pos += 1;
+                let value = Self::parse_or(chars, pos)?;
+                Self::skip_spaces(chars, pos);
+                if chars.get(*pos) != Some(&')') {
+                    Err("Unbalanced parenthesis in condition")?
+                }
+                *pos += 1;
+                Ok(value)
+            }
+            Some('\'') => {
+                *pos += 1;
+                let text: String = chars[*pos..].iter().take_while(|c| **c != '\'').collect();
+                *pos += text.chars().count();
+                if chars.get(*pos) != Some(&'\'') {
+                    Err("Unterminated string in condition")?
+                }
+                *pos += 1;
+                Ok(Value::Text(text))
+            }
+            _ if word == "X" && chars.get(*pos + 1) == Some(&'\'') => {
+                *pos += 2;
+                let digits: String = chars[*pos..].iter().take_while(|c| **c != '\'').collect();
+                *pos += digits.chars().count() + 1;
+                Ok(Value::Integer(i64::from_str_radix(&digits, 16)?))
+            }
+            _ if word.is_empty() => {
+                Err(format!("Invalid condition {}", chars.iter().collect::<String>().trim()))?
+            }
+            _ => {
+                *pos += word.chars().count();
+                match word.parse() {
+                    Ok(num) => {
+                        Ok(Value::Integer(num))
+                    }
+                    Err(_) => {
+                        Ok(Value::Text(word))
+                    }
+                }
+            }
+        }
+    }
+}
+
+enum Conditional {
+    // `done` is set once one branch of the IF has been taken
+    If{start: usize, active: bool, done: bool},
+    // `iterations` counts the times the body of this loop has been assembled
+    While{start: usize, active: bool, iterations: usize}
+}
+
+impl Conditional {
+    fn is_active(self: &Self) -> bool {
+        match self {
+            Conditional::If{active, ..} | Conditional::While{active, ..} => {
+                *active
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct MacroProcessor {
+    macros: HashMap<String, Macro>,
+    // assembly time variables defined with SET, without the leading '&'
+    variables: HashMap<String, String>,
+    invocations: usize
+}
+
+impl MacroProcessor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // split an operand field on the commas that are not inside quotes or parentheses
+    fn split_arguments(operand: &str) -> Vec<String> {
+        let mut res = vec![];
+        let mut argument = String::new();
+        let mut in_str = false;
+        let mut depth = 0;
+
+        for c in operand.chars() {
+            match c {
+                '\'' => {
+                    in_str = !in_str;
+                    argument.push(c);
+                }
+                '(' if !in_str => {
+                    depth += 1;
+                    argument.push(c);
+                }
+                ')' if !in_str => {
+                    depth -= 1;
+                    argument.push(c);
+                }
+                ',' if !in_str && depth == 0 => {
+                    res.push(argument);
+                    argument = String::new();
+                }
+                _ => {
+                    argument.push(c);
+                }
+            }
+        }
+
+        res.push(argument);
+        res
+    }
+
+    fn is_definition(lexemes: &[String]) -> bool {
+        lexemes.len() >= 2 && lexemes[1] == "MACRO"
+    }
+
+    fn is_mend(lexemes: &[String]) -> bool {
+        lexemes.first().map(|lexeme| lexeme == "MEND").unwrap_or(false)
+    }
+
+    // the index of the MEND that closes the definition starting at `start`
+    fn find_mend(lines: &[String], start: usize) -> Option<usize> {
+        // definitions may be nested, only the matching MEND closes this one
+        let mut depth = 0;
+
+        for (idx, line) in lines.iter().enumerate().skip(start + 1) {
+            let lexemes = lexer::parse_line_to_lexemes(line);
+
+            if Self::is_definition(&lexemes) {
+                depth += 1;
+            } else if Self::is_mend(&lexemes) {
+                if depth == 0 {
+                    return Some(idx);
+                }
+                depth -= 1;
+            }
+        }
+
+        None
+    }
+
+    // the definition from the prototype on line `start` to its MEND on line `end`
+    fn read_definition(lines: &[String], start: usize, end: usize) -> Result<Macro, Diagnostic> {
+        let lexemes = lexer::parse_line_to_lexemes(&lines[start]);
+        let name = lexemes[0].clone();
+        let mut parameters = vec![];
+
+        if lexemes.len() > 3 {
+            Err(Self::line_error(&lines[start], 3, format!("Invalid prototype for macro {}", name)))?
+        }
+
+        if let Some(operand) = lexemes.get(2) {
+            for parameter in Self::split_arguments(operand) {
+                let (parameter, default) = match parameter.split_once('=') {
+                    Some((parameter, default)) => (parameter, Some(String::from(default))),
+                    None => (parameter.as_str(), None)
+                };
+
+                match parameter.strip_prefix('&') {
+                    Some(parameter) if !parameter.is_empty() => {
+                        parameters.push((String::from(parameter), default));
+                    }
+                    _ => {
+                        Err(Self::line_error(&lines[start], 2, format!("Parameter {} of macro {} must start with &", parameter, name)))?
+                    }
+                }
+            }
+        }
+
+        Ok(Macro{name, parameters, body: lines[start + 1..end].to_vec()})
+    }
+
+    // points at the field-th field of the line and everything after it, up to the comment
+    fn line_error(line: &str, field: usize, message: impl Into<String>) -> Diagnostic {
+        let spans = lexer::parse_line_to_spans(line);
+        let diagnostic = Diagnostic::error(message);
+
+        match (spans.get(field).or(spans.last()), spans.last()) {
+            (Some((start, _)), Some((last, lexeme))) => {
+                diagnostic.with_span(*start, last + lexeme.chars().count() - start)
+            }
+            _ => {
+                diagnostic
+            }
+        }
+    }
+
+    // replace every &PARAMETER, and every $LABEL when expanding a macro body
+    fn substitute(line: &str, arguments: &HashMap<String, String>, unique: Option<&str>) -> String {
+        let chars: Vec<char> = line.chars().collect();
+        let mut res = String::new();
+        let mut in_str = false;
+        let mut idx = 0;
+
+        while idx < chars.len() {
+            let c = chars[idx];
+
+            if c == '&' || c == '$' {
+                let start = idx + 1;
+                let mut end = start;
+                while end < chars.len() && (chars[end].is_alphanumeric() || chars[end] == '_') {
+                    end += 1;
+                }
+                let name: String = chars[start..end].iter().collect();
+
+                if c == '&' && arguments.contains_key(&name) {
+                    res += &arguments[&name];
+                    idx = end;
+                    // -> only separates a parameter from the text glued to it
+                    if !in_str && chars.get(idx) == Some(&'-') && chars.get(idx + 1) == Some(&'>') {
+                        idx += 2;
+                    }
+                    continue;
+                } else if c == '$' && unique.is_some() && !name.is_empty() {
+                    res += &format!("${}{}", unique.unwrap(), name);
+                    idx = end;
+                    continue;
+                }
+            }
+
+            if c == '\'' {
+                in_str = !in_str;
+            }
+
+            res.push(c);
+            idx += 1;
+        }
+
+        res
+    }
+
+    // two letters per invocation like the textbook: AA, AB, ..., ZZ
+    fn unique_id(self: &mut Self) -> Result<String, Box<dyn Error>> {
+        if self.invocations >= 26 * 26 {
+            Err("Too many macro invocations for unique labels")?
+        }
+
+        let id = self.invocations;
+        self.invocations += 1;
+        Ok(format!("{}{}", (b'A' + (id / 26) as u8) as char, (b'A' + (id % 26) as u8) as char))
+    }
+
+    fn expand(self: &mut Self, name: &str, label: Option<&String>, operand: Option<&String>) -> Result<Vec<String>, Box<dyn Error>> {
+        let unique = self.unique_id()?;
+        let definition = &self.macros[name];
+        let mut arguments = HashMap::new();
+        let mut positional = 0;
+
+        for (parameter, default) in &definition.parameters {
+            if let Some(default) = default {
+                arguments.insert(parameter.clone(), default.clone());
+            }
+        }
+
+        if let Some(operand) = operand {
+            for argument in Self::split_arguments(operand) {
+                // NAME=value sets a keyword parameter, anything else is the next positional one
+                let keyword = argument.split_once('=')
+                    .filter(|(parameter, _)| definition.parameters.iter().any(|(name, _)| name == parameter));
+
+                if let Some((parameter, value)) = keyword {
+                    arguments.insert(String::from(parameter), String::from(value));
+                } else if let Some((parameter, _)) = definition.parameters.get(positional) {
+                    arguments.insert(parameter.clone(), argument);
+                    positional += 1;
+                } else {
+                    Err(format!("Too many arguments for macro {}", name))?
+                }
+            }
+        }
+
+        for (parameter, _) in &definition.parameters {
+            if !arguments.contains_key(parameter) {
+                arguments.insert(parameter.clone(), String::new());
+            }
+        }
+
+        let mut res: Vec<String> = definition.body.iter()
+            .map(|line| Self::substitute(line, &arguments, Some(&unique)))
+            .collect();
+
+        // the label of the call goes to the first generated statement, or gets its own EQU *
+        if let Some(label) = label {
+            let first = res.iter().position(|line| !lexer::parse_line_to_lexemes(line).is_empty());
+            match first {
+                Some(idx) if Self::starts_with_command(&res[idx]) => {
+                    res[idx] = format!("{} {}", label, res[idx]);
+                }
+                _ => {
+                    res.insert(0, format!("{} EQU *", label));
+                }
+            }
+        }
+
+        Ok(res)
+    }
+
+    fn starts_with_command(line: &str) -> bool {
+        match lexer::parse_line_to_lexemes(line).first() {
+            Some(lexeme) => {
+                COMMANDS.contains_key(lexeme.trim_start_matches('+'))
+            }
+            None => {
+                false
+            }
+        }
+    }
+
+    // lines that cannot be processed are reported and left out, so that the rest is still checked
+    fn process_lines(self: &mut Self, lines: &[String], depth: usize, diagnostics: &mut Diagnostics) -> Vec<SourceLine> {
+        let mut res = vec![];
+        let mut conditionals: Vec<Conditional> = vec![];
+        // the WHILE an ENDW went back to, with the iterations of its loop so far
+        let mut repeated = None;
+        let mut idx = 0;
+
+        while idx < lines.len() {
+            let number = idx + 1;
+            let text = &lines[idx];
+            let lexemes = lexer::parse_line_to_lexemes(text);
+            let active = conditionals.iter().all(Conditional::is_active);
+
+            if Self::is_definition(&lexemes) {
+                let end = match Self::find_mend(lines, idx) {
+                    Some(end) => end,
+                    None => {
+                        // everything that follows would be part of the body
+                        diagnostics.push(Self::line_error(text, 1, format!("Missing MEND for macro {}", lexemes[0])).on_line(number, text));
+                        return res;
+                    }
+                };
+
+                if active {
+                    for (offset, line) in lines[idx..=end].iter().enumerate() {
+                        res.push(SourceLine{number: number + offset, text: line.clone(), kind: LineKind::Definition});
+                    }
+
+                    match Self::read_definition(lines, idx, end) {
+                        Ok(definition) => {
+                            self.macros.insert(definition.name.clone(), definition);
+                        }
+                        Err(diagnostic) => {
+                            diagnostics.push(diagnostic.on_line(number, text));
+                        }
+                    }
+                }
+
+                idx = end + 1;
+                continue;
+            }
+
+            let control = match lexemes.first().map(String::as_str) {
+                Some("IF") => {
+                    let condition = active && match self.evaluate_control(text, "IF") {
+                        Ok(value) => value.is_true(),
+                        Err(diagnostic) => {
+                            diagnostics.push(diagnostic.on_line(number, text));
+                            // neither branch is assembled
+                            conditionals.push(Conditional::If{start: idx, active: false, done: true});
+                            idx += 1;
+                            continue;
+                        }
+                    };
+                    conditionals.push(Conditional::If{start: idx, active: condition, done: condition || !active});
+                    true
+                }
+                Some("ELSE") => {
+                    match conditionals.last_mut() {
+                        Some(Conditional::If{active, done, ..}) => {
+                            *active = !*done;
+                            *done = true;
+                            true
+                        }
+                        _ => {
+                            diagnostics.push(Self::line_error(text, 0, "ELSE without IF").on_line(number, text));
+                            false
+                        }
+                    }
+                }
+                Some("ENDIF") => {
+                    match conditionals.last() {
+                        Some(Conditional::If{..}) => {
+                            conditionals.pop();
+                            true
+                        }
+                        _ => {
+                            diagnostics.push(Self::line_error(text, 0, "ENDIF without IF").on_line(number, text));
+                            false
+                        }
+                    }
+                }
+                Some("WHILE") => {
+                    let condition = active && match self.evaluate_control(text, "WHILE") {
+                        Ok(value) => value.is_true(),
+                        Err(diagnostic) => {
+                            diagnostics.push(diagnostic.on_line(number, text));
+                            false
+                        }
+                    };
+                    let iterations = match repeated.take() {
+                        Some((start, iterations)) if start == idx => iterations,
+                        _ => 0
+                    };
+                    conditionals.push(Conditional::While{start: idx, active: condition, iterations});
+                    true
+                }
+                Some("ENDW") => {
+                    match conditionals.last() {
+                        Some(Conditional::While{start, active: true, iterations}) => {
+                            let (start, iterations) = (*start, *iterations + 1);
+                            conditionals.pop();
+                            if iterations > MAX_ITERATIONS {
+                                diagnostics.push(Self::line_error(&lines[start], 1, "WHILE does not terminate").on_line(start + 1, &lines[start]));
+                            } else {
+                                res.push(SourceLine{number, text: text.clone(), kind: LineKind::Control});
+                                // go back and test the condition again
+                                repeated = Some((start, iterations));
+                                idx = start;
+                                continue;
+                            }
+                            true
+                        }
+                        Some(Conditional::While{..}) => {
+                            conditionals.pop();
+                            true
+                        }
+                        _ => {
+                            diagnostics.push(Self::line_error(text, 0, "ENDW without WHILE").on_line(number, text));
+                            false
+                        }
+                    }
+                }
+                _ if lexemes.len() >= 2 && lexemes[1] == "SET" => {
+                    match lexemes[0].strip_prefix('&') {
+                        Some(variable) if !variable.is_empty() => {
+                            if active {
+                                match self.evaluate_control(text, "SET") {
+                                    Ok(value) => {
+                                        self.variables.insert(String::from(variable), value.to_string());
+                                    }
+                                    Err(diagnostic) => {
+                                        diagnostics.push(diagnostic.on_line(number, text));
+                                    }
+                                }
+                            }
+                        }
+                        _ => {
+                            diagnostics.push(Self::line_error(text, 0, "SET needs an &VARIABLE label").on_line(number, text));
+                        }
+                    }
+                    true
+                }
+                _ => {
+                    false
+                }
+            };
+
+            if control {
+                // ELSE and ENDIF are listed whenever the code around their IF is
+                let listed = match lexemes[0].as_str() {
+                    "ELSE" => {
+                        conditionals[..conditionals.len() - 1].iter().all(Conditional::is_active)
+                    }
+                    "ENDIF" | "ENDW" => {
+                        conditionals.iter().all(Conditional::is_active)
+                    }
+                    _ => {
+                        active
+                    }
+                };
+
+                if listed {
+                    res.push(SourceLine{number, text: text.clone(), kind: LineKind::Control});
+                }
+                idx += 1;
+                continue;
+            }
+
+            // a misplaced ELSE, ENDIF or ENDW has been reported already
+            if !active || matches!(lexemes.first().map(String::as_str), Some("ELSE" | "ENDIF" | "ENDW")) {
+                idx += 1;
+                continue;
+            }
+
+            let line = Self::substitute(text, &self.variables, None);
+            let lexemes = lexer::parse_line_to_lexemes(&line);
+
+            let is_command = |idx: usize| lexemes.get(idx).map(|lexeme| COMMANDS.contains_key(lexeme.trim_start_matches('+'))).unwrap_or(false);
+            let is_macro = |idx: usize| lexemes.get(idx).map(|lexeme| self.macros.contains_key(lexeme)).unwrap_or(false);
+
+            // the command is the first or the second field like in Expression::from_str, so a macro name
+            // before a mnemonic or directive is a label and one after them is an operand
+            let call = if is_macro(0) && !is_command(1) {
+                Some((None, 0))
+            } else if is_macro(1) && !is_command(0) {
+                Some((Some(&lexemes[0]), 1))
+            } else {
+                None
+            };
+
+            if let Some((label, pos)) = call {
+                res.push(SourceLine{number, text: line.clone(), kind: LineKind::Call});
+
+                if lexemes.len() > pos + 2 {
+                    diagnostics.push(Self::line_error(&line, pos + 2, format!("Invalid call of macro {}", lexemes[pos])).on_line(number, &line));
+                } else if depth >= MAX_DEPTH {
+                    diagnostics.push(Self::line_error(&line, pos, "Macro expansion is nested too deeply").on_line(number, &line)
+                        .with_note(format!("macros may call each other up to {} levels deep", MAX_DEPTH)));
+                } else {
+                    match self.expand(&lexemes[pos], label, lexemes.get(pos + 1)) {
+                        Ok(expanded) => {
+                            // the expansion may define or call other macros itself
+                            let mut inner = vec![];
+                            for mut line in self.process_lines(&expanded, depth + 1, &mut inner) {
+                                line.number = number;
+                                if line.kind == LineKind::Source {
+                                    line.kind = LineKind::Expansion;
+                                }
+                                res.push(line);
+                            }
+
+                            // reported on the line of the call, with the text of the generated line
+                            for mut diagnostic in inner {
+                                if let Some((_, text)) = diagnostic.line.take() {
+                                    diagnostic.line = Some((number, text));
+                                }
+                                if !diagnostic.notes.contains(&String::from(EXPANSION_NOTE)) {
+                                    diagnostic.notes.push(String::from(EXPANSION_NOTE));
+                                }
+                                diagnostics.push(diagnostic);
+                            }
+                        }
+                        Err(message) => {
+                            diagnostics.push(Self::line_error(&line, pos + 1, message.to_string()).on_line(number, &line));
+                        }
+                    }
+                }
+            } else if lexemes.is_empty() {
+                res.push(SourceLine{number, text: line, kind: LineKind::Comment});
+            } else {
+                res.push(SourceLine{number, text: line, kind: LineKind::Source});
+            }
+
+            idx += 1;
+        }
+
+        for conditional in conditionals {
+            let (start, message) = match conditional {
+                Conditional::If{start, ..} => (start, "Missing ENDIF"),
+                Conditional::While{start, ..} => (start, "Missing ENDW")
+            };
+            diagnostics.push(Self::line_error(&lines[start], 0, message).on_line(start + 1, &lines[start]));
+        }
+
+        res
+    }
+
+    // evaluates whatever follows the keyword of an IF, WHILE or SET line
+    fn evaluate_control(self: &Self, line: &str, keyword: &str) -> Result<Value, Diagnostic> {
+        let field = if keyword == "SET" { 2 } else { 1 };
+        let substituted = Self::substitute(line, &self.variables, None);
+        // the rest of the line from the field after the keyword, the label may contain the keyword as well
+        let operand: String = match lexer::parse_line_to_spans(&substituted).get(field) {
+            Some((start, _)) => substituted.chars().skip(*start).collect(),
+            None => String::new()
+        };
+
+        if let Some(variable) = operand.split(|c: char| !(c.is_alphanumeric() || c == '_' || c == '&')).find(|word| word.starts_with('&')) {
+            Err(Self::line_error(line, field, format!("Undefined variable {} in {}", variable, keyword)))?
+        }
+
+        Value::parse(&operand).map_err(|err| Self::line_error(line, field, err.to_string()))
+    }
+
+    pub fn process(self: &mut Self, lines: &[String], diagnostics: &mut Diagnostics) -> Vec<SourceLine> {
+        self.process_lines(lines, 0, diagnostics)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(source: &str) -> Vec<String> {
+        source.lines().map(String::from).collect()
+    }
+
+    // the text of the lines left for the assembler and the line and message of every diagnostic
+    fn process(source: &str) -> (Vec<String>, Vec<(usize, String)>) {
+        let mut diagnostics = vec![];
+        let res = MacroProcessor::new().process(&lines(source), &mut diagnostics);
+        let texts = res.into_iter().filter(|line| line.kind == LineKind::Source || line.kind == LineKind::Expansion).map(|line| line.text).collect();
+
+        (texts, diagnostics.into_iter().map(|diagnostic| (diagnostic.line.unwrap().0, diagnostic.message)).collect())
+    }
+
+    #[test]
+    fn arrow_only_ends_a_parameter() {
+        let arguments = HashMap::from([(String::from("SUF"), String::from("1"))]);
+
+        assert_eq!(MacroProcessor::substitute("TAB&SUF->X WORD 1", &arguments, None), "TAB1X WORD 1");
+        assert_eq!(MacroProcessor::substitute("STR BYTE C'A->B'", &arguments, None), "STR BYTE C'A->B'");
+        assert_eq!(MacroProcessor::substitute("MSG BYTE C'&SUF->B'", &arguments, None), "MSG BYTE C'1->B'");
+        assert_eq!(MacroProcessor::substitute("X->Y WORD 1", &arguments, None), "X->Y WORD 1");
+    }
+
+    #[test]
+    fn conditions() {
+        assert!(Value::parse("(3 GT 2) AND NOT (1 EQ 2)").unwrap().is_true());
+        assert_eq!(Value::parse("2*(3+4)-20/5").unwrap().to_string(), "10");
+        assert_eq!(Value::parse("9223372036854775807+1").err().unwrap().to_string(), "Arithmetic overflow in condition");
+        assert_eq!(Value::parse("-9223372036854775807-2").err().unwrap().to_string(), "Arithmetic overflow in condition");
+        assert_eq!(Value::parse("1/0").err().unwrap().to_string(), "Division by zero in condition");
+    }
+
+    #[test]
+    fn while_and_set() {
+        let source = "&N      SET     0
+        WHILE   (&N LT 3)
+        LDA     #&N
+&N      SET     &N+1
+        ENDW
+        IF      (&N EQ 3)
+        RSUB
+        ELSE
+        J       *
+        ENDIF";
+        let (texts, diagnostics) = process(source);
+
+        assert_eq!(texts, ["        LDA     #0", "        LDA     #1", "        LDA     #2", "        RSUB"]);
+        assert!(diagnostics.is_empty());
+    }
+
+    #[test]
+    fn every_loop_has_its_own_limit() {
+        // the inner body is assembled 90000 times, but no loop goes round more than 300 times
+        let source = "&I      SET     0
+        WHILE   (&I LT 300)
+&J      SET     0
+        WHILE   (&J LT 300)
+&J      SET     &J+1
+        ENDW
+&I      SET     &I+1
+        ENDW
+        WORD    &I";
+        let (texts, diagnostics) = process(source);
+
+        assert_eq!(texts, ["        WORD    300"]);
+        assert!(diagnostics.is_empty());
+
+        let (_, diagnostics) = process("        WHILE   (1 EQ 1)\n        ENDW");
+        assert_eq!(diagnostics, [(1, String::from("WHILE does not terminate"))]);
+    }
+
+    #[test]
+    fn variables_may_contain_keywords() {
+        let source = "&OFFSET SET     3
+&RESET  SET     &OFFSET+1
+&SETIF  SET     1
+        IF      (&SETIF EQ 1)
+        LDA     #&OFFSET
+        LDX     #&RESET
+        ENDIF";
+        let (texts, diagnostics) = process(source);
+
+        assert_eq!(texts, ["        LDA     #3", "        LDX     #4"]);
+        assert!(diagnostics.is_empty());
+    }
+
+    #[test]
+    fn expansion() {
+        let source = "RD      MACRO   &DEV,&LEN=3
+$LOOP   TD      =X'&DEV'
+        JEQ     $LOOP
+        LDT     #&LEN
+        MEND
+FIRST   RD      F1
+        RD      05,LEN=4";
+        let (texts, diagnostics) = process(source);
+
+        // the label of the call names the start of the expansion
+        assert_eq!(texts, [
+            "FIRST EQU *", "$AALOOP   TD      =X'F1'", "        JEQ     $AALOOP", "        LDT     #3",
+            "$ABLOOP   TD      =X'05'", "        JEQ     $ABLOOP", "        LDT     #4"
+        ]);
+        assert!(diagnostics.is_empty());
+    }
+
+    #[test]
+    fn macro_names_as_labels_and_operands() {
+        let source = "RDBUFF  MACRO
+        TD      =X'F1'
+        MEND
+        J       RDBUFF
+RDBUFF  RESB    1
+FIRST   RDBUFF";
+        let (texts, diagnostics) = process(source);
+
+        assert_eq!(texts, ["        J       RDBUFF", "RDBUFF  RESB    1", "FIRST         TD      =X'F1'"]);
+        assert!(diagnostics.is_empty());
+    }
+
+    #[test]
+    fn errors_are_collected() {
+        let source = "&X      SET     99999*99999
+&Y      SET     &X*&X
+        ELSE
+        ENDW
+        LDA     #1
+        IF      (&Q EQ 1)
+        RSUB";
+        let (texts, diagnostics) = process(source);
+
+        assert_eq!(texts, ["        LDA     #1"]);
+        assert_eq!(diagnostics, [
+            (2, String::from("Arithmetic overflow in condition")),
+            (3, String::from("ELSE without IF")),
+            (4, String::from("ENDW without WHILE")),
+            (6, String::from("Undefined variable &Q in IF")),
+            (6, String::from("Missing ENDIF"))
+        ]);
+    }
+
+    #[test]
+    fn errors_in_expansions_are_reported_at_the_call() {
+        let source = "BAD     MACRO   &A
+        IF      (&A EQ
+        ENDIF
+        MEND
+        LDA     #0
+        BAD     1";
+        let mut diagnostics = vec![];
+        MacroProcessor::new().process(&lines(source), &mut diagnostics);
+
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].line.as_ref().unwrap().0, 6);
+        assert_eq!(diagnostics[0].notes, [EXPANSION_NOTE]);
+    }
+}
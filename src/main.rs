@@ -1,56 +1,384 @@
-mod parser;
-mod lexer;
-
 use std::env;
-use std::fs::File;
-use parser::ParserData;
+use std::fs;
+use std::error::Error;
+use std::fmt::Display;
+use std::io::{self, Seek, SeekFrom, Write};
+use sicxe_assembler::{AssembledProgram, Debugger, LoadedProgram, Mode, ObjectProgram, Options, Severity, Simulator, SourceFormat};
 
 
+const USAGE: &str = "Usage: sicxe-assembler [OPTIONS] <FILE>
+       sicxe-assembler disasm <OBJECT_FILE>
+       sicxe-assembler check <OBJECT_FILE>...
+       sicxe-assembler link [--address <HEX>] [--object] <OBJECT_FILE>...
+       sicxe-assembler run [--sic] [--fixed] [--relax] [--input <FILE>] [--steps <N>] <FILE>
+       sicxe-assembler debug [--sic] [--fixed] [--relax] [--input <FILE>] [--steps <N>] <FILE>
+";
 
+// for a command line that lacks the files it needs
+fn usage() -> ! {
+    eprint!("{}", USAGE);
+    std::process::exit(1);
+}
 
-fn main() {
+fn fail(message: impl Display) -> ! {
+    eprintln!("error: {}", message);
+    std::process::exit(1);
+}
 
-    let args: Vec<String> = env::args().collect();
-    let file_path = &args[1];
+fn read_file(path: &str) -> String {
+    fs::read_to_string(path).unwrap_or_else(|err| fail(format!("cannot read {}: {}", path, err)))
+}
 
-    if args.len() <= 1 {
-        println!("File name not specify");
-        return;
+fn assemble(file_path: &str, options: Options) -> AssembledProgram {
+    let source = read_file(file_path);
+
+    match sicxe_assembler::assemble(&source, &options) {
+        Ok(data) => data,
+        Err(diagnostics) => {
+            for diagnostic in &diagnostics {
+                eprintln!("{}", diagnostic.render(file_path));
+            }
+            match diagnostics.len() {
+                1 => eprintln!("error: aborting due to 1 previous error"),
+                count => eprintln!("error: aborting due to {} previous errors", count)
+            }
+            std::process::exit(1);
+        }
+    }
+}
+
+fn read_object(file_path: &str, source: &str) -> Vec<ObjectProgram> {
+    match ObjectProgram::read(source) {
+        Ok(programs) => programs,
+        Err(err) => {
+            eprintln!("{}: {}", file_path, err);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn disassemble(file_path: &str) {
+    let source = read_file(file_path);
+
+    let programs = read_object(file_path, &source);
+
+    for line in sicxe_assembler::disassemble(&programs) {
+        println!("{}", line);
+    }
+}
+
+// reports every problem of the object files, exits with 1 if one of them has errors
+fn check(paths: &[String]) {
+    let mut errors = 0;
+
+    for path in paths {
+        let source = read_file(path);
+        let (programs, diagnostics) = ObjectProgram::check(&source);
+
+        for diagnostic in &diagnostics {
+            eprintln!("{}", diagnostic.render(path));
+        }
+        errors += diagnostics.iter().filter(|diagnostic| diagnostic.severity == Severity::Error).count();
+
+        for program in &programs {
+            let header = &program.header;
+            println!("{}: {:<6} {:06X} {:06X} {} text, {} modification records", path, header.program_name, header.start_address, header.len, program.texts.len(), program.modifications.len());
+        }
+    }
+
+    match errors {
+        0 => {}
+        1 => {
+            eprintln!("error: 1 error found");
+            std::process::exit(1);
+        }
+        count => {
+            eprintln!("error: {} errors found", count);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn link(args: &[String]) {
+    let mut address = 0;
+    let mut absolute = false;
+    let mut programs = vec![];
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--address" => {
+                address = args.next().and_then(|address| u64::from_str_radix(address, 16).ok()).unwrap_or_else(|| fail("--address needs a hex address"));
+            }
+            "--object" => {
+                absolute = true;
+            }
+            _ => {
+                let source = read_file(arg);
+                programs.extend(read_object(arg, &source));
+            }
+        }
+    }
+
+    if programs.is_empty() {
+        usage();
+    }
+
+    let program = match LoadedProgram::link(&programs, address) {
+        Ok(program) => program,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    let lines = if absolute {
+        program.object_lines()
+    } else {
+        let mut lines = program.estab_lines();
+        lines.push(String::new());
+        lines.extend(program.memory_lines());
+        lines
+    };
+
+    for line in lines {
+        println!("{}", line);
+    }
+}
+
+// every section loaded one after another from the start address of the first one
+fn link_image(data: &AssembledProgram) -> Result<LoadedProgram, Box<dyn Error>> {
+    let programs = data.object_programs();
+    let address = programs.first().map(|program| program.header.start_address).unwrap_or(0);
+
+    LoadedProgram::link(&programs, address)
+}
+
+// reserved space is zero unless gaps leaves it unwritten, which also ends the image with the last byte of code
+fn write_binary(program: &LoadedProgram, path: &str, gaps: bool) -> Result<(), Box<dyn Error>> {
+    let mut file = fs::File::create(path)?;
+
+    if gaps {
+        for (start, len) in program.runs() {
+            file.seek(SeekFrom::Start(start as u64))?;
+            file.write_all(&program.bytes[start..start + len])?;
+        }
+    } else {
+        file.write_all(&program.bytes)?;
     }
 
-    println!("File name: {}", file_path);
+    Ok(())
+}
+
+fn write_image(data: &AssembledProgram, binary_path: Option<&String>, gaps: bool, ihex_path: Option<&String>, srec_path: Option<&String>) -> Result<(), Box<dyn Error>> {
+    let program = link_image(data)?;
+
+    if let Some(path) = binary_path {
+        write_binary(&program, path, gaps).map_err(|err| format!("cannot write {}: {}", path, err))?;
+    }
+
+    let outputs = [(ihex_path, sicxe_assembler::intel_hex(&program)), (srec_path, sicxe_assembler::s_records(&program))];
+    for (path, lines) in outputs {
+        if let Some(path) = path {
+            let text: String = lines.iter().map(|line| format!("{}\n", line)).collect();
+            fs::write(path, text).map_err(|err| format!("cannot write {}: {}", path, err))?;
+        }
+    }
+
+    Ok(())
+}
+
+// assembles the program and loads it for run and debug, returns the step limit too
+fn load(args: &[String]) -> (AssembledProgram, Simulator, usize) {
+    let mut options = Options::default();
+    let mut file_path = None;
+    let mut input = vec![];
+    let mut max_steps = 1_000_000;
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--sic" => {
+                options.mode = Mode::SIC;
+            }
+            "--fixed" => {
+                options.format = SourceFormat::Fixed;
+            }
+            "--relax" => {
+                options.relax = true;
+            }
+            "--input" => {
+                let path = args.next().unwrap_or_else(|| fail("--input needs a file"));
+                input = fs::read(path).unwrap_or_else(|err| fail(format!("cannot read {}: {}", path, err)));
+            }
+            "--steps" => {
+                max_steps = args.next().and_then(|steps| steps.parse().ok()).unwrap_or_else(|| fail("--steps needs a number"));
+            }
+            _ => {
+                file_path = Some(arg);
+            }
+        }
+    }
+
+    let file_path = file_path.unwrap_or_else(|| usage());
+    let data = assemble(file_path, options);
+
+    let mut simulator = Simulator::new();
+    if let Err(err) = simulator.load(&data) {
+        eprintln!("error: cannot load {}: {}", file_path, err);
+        std::process::exit(1);
+    }
+    // F1 is the input device of the textbook programs
+    simulator.device(0xF1).input.extend(input);
+
+    (data, simulator, max_steps)
+}
+
+fn run(args: &[String]) {
+    let (_, mut simulator, max_steps) = load(args);
+
+    let result = simulator.run(max_steps);
+
+    println!("{}", simulator.registers);
+    println!("{} steps", simulator.steps);
+
+    let mut ids: Vec<&u8> = simulator.devices.keys().collect();
+    ids.sort();
+    for id in ids {
+        let output = &simulator.devices[id].output;
+        if !output.is_empty() {
+            println!("device {:02X}: {}", id, String::from_utf8_lossy(output));
+        }
+    }
+
+    if let Err(err) = result {
+        eprintln!("error: {}", err);
+        std::process::exit(1);
+    }
+}
 
-    let file_content = File::open(file_path)
-        .expect("File not exist or cannot be opened");
+fn debug(args: &[String]) {
+    let (data, simulator, max_steps) = load(args);
 
+    Debugger::new(&data, simulator, max_steps).run(io::stdin().lock());
+}
 
-    let data = ParserData::from_file(file_content).unwrap();
-    let (mut line, mut address) = (0, data.header.start_address);
+fn main() {
 
+    let args: Vec<String> = env::args().collect();
 
-    println!("{:>04} {:>08} {:>12} {:>12} {:>12} {}", "line", "address", "label", "operate", "operand", "opcode");
+    if args.len() == 1 || (args.len() == 2 && ["disasm", "check", "link", "run", "debug"].contains(&args[1].as_str())) {
+        usage();
+    }
 
-    println!("{:>04} {:>8X} {:>12} {:>12} {:>12X}", line, address, data.header.program_name, "START", data.header.start_address);
+    if args.len() > 2 && args[1] == "disasm" {
+        disassemble(&args[2]);
+        return;
+    }
 
-    line += 1;
+    if args.len() > 2 && args[1] == "check" {
+        check(&args[2..]);
+        return;
+    }
 
-    for text in &data.texts {
-        for (expression, opcode) in &text.expressions {
+    if args.len() > 2 && args[1] == "link" {
+        link(&args[2..]);
+        return;
+    }
 
-            println!("{:>04} {:>8X} {} {:>}", line, address, expression, opcode);
+    if args.len() > 2 && args[1] == "run" {
+        run(&args[2..]);
+        return;
+    }
+
+    if args.len() > 2 && args[1] == "debug" {
+        debug(&args[2..]);
+        return;
+    }
+    let mut options = Options::default();
+    let mut file_path = None;
+    let mut object_path = None;
+    let mut listing_path = None;
+    let mut symbols_path = None;
+    let mut json_path = None;
+    let mut binary_path = None;
+    let mut gaps = false;
+    let mut ihex_path = None;
+    let mut srec_path = None;
+    let mut rest = args[1..].iter();
 
-            line += 1;
-            address += expression.len() as u64;
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--sic" => {
+                options.mode = Mode::SIC;
+            }
+            "--fixed" => {
+                options.format = SourceFormat::Fixed;
+            }
+            "--relax" => {
+                options.relax = true;
+            }
+            "-o" => {
+                object_path = Some(rest.next().unwrap_or_else(|| fail("-o needs a file")));
+            }
+            "--listing" => {
+                listing_path = Some(rest.next().unwrap_or_else(|| fail("--listing needs a file")));
+            }
+            "--symbols" => {
+                symbols_path = Some(rest.next().unwrap_or_else(|| fail("--symbols needs a file")));
+            }
+            "--json" => {
+                json_path = Some(rest.next().unwrap_or_else(|| fail("--json needs a file")));
+            }
+            "--binary" => {
+                binary_path = Some(rest.next().unwrap_or_else(|| fail("--binary needs a file")));
+            }
+            "--gaps" => {
+                gaps = true;
+            }
+            "--ihex" => {
+                ihex_path = Some(rest.next().unwrap_or_else(|| fail("--ihex needs a file")));
+            }
+            "--srec" => {
+                srec_path = Some(rest.next().unwrap_or_else(|| fail("--srec needs a file")));
+            }
+            _ => {
+                file_path = Some(arg);
+            }
         }
     }
 
-    println!("{:>04} {:>8} {:>12} {:>12} {:>12}", line, "", "", "END", data.end.start_label);
+    let file_path = file_path.unwrap_or_else(|| usage());
+
+    // without output files everything goes to stdout like it always did
+    let images = binary_path.is_some() || ihex_path.is_some() || srec_path.is_some();
+    if object_path.is_none() && listing_path.is_none() && symbols_path.is_none() && json_path.is_none() && !images {
+        println!("File name: {}", file_path);
+
+        let data = assemble(file_path, options);
 
-    println!("{}", data.header);
+        print!("{}", data.listing());
+        println!();
+        print!("{}", data.records());
+        return;
+    }
+
+    let data = assemble(file_path, options);
 
-    for text in &data.texts {
-        println!("{}", text);
+    let outputs = [(object_path, data.records()), (listing_path, data.listing()), (symbols_path, data.symbol_table()), (json_path, data.json())];
+    for (path, text) in outputs {
+        if let Some(path) = path {
+            if let Err(err) = fs::write(path, text) {
+                eprintln!("error: cannot write {}: {}", path, err);
+                std::process::exit(1);
+            }
+        }
     }
 
-    println!{"{}", data.end};
+    if images {
+        if let Err(err) = write_image(&data, binary_path, gaps, ihex_path, srec_path) {
+            eprintln!("error: {}", err);
+            std::process::exit(1);
+        }
+    }
 }
@@ -0,0 +1,334 @@
+use std::collections::HashSet;
+use std::error::Error;
+use crate::diagnostic::{Diagnostic, Diagnostics};
+use crate::parser::{HeaderSection, ModificationSection};
+
+pub struct TextRecord {
+    pub address: u64,
+    pub bytes: Vec<u8>
+}
+
+// one control section of an object file, as written by the Display impls in parser.rs
+pub struct ObjectProgram {
+    pub header: HeaderSection,
+    pub defines: Vec<(String, u64)>,
+    pub references: Vec<String>,
+    pub texts: Vec<TextRecord>,
+    pub modifications: Vec<ModificationSection>,
+    pub start_address: Option<u64>
+}
+
+impl ObjectProgram {
+    fn field(record: &str, start: usize, len: usize) -> Result<&str, Diagnostic> {
+        match record.get(start..start + len) {
+            Some(field) => {
+                Ok(field)
+            }
+            None => {
+                Err(Diagnostic::error("Record is too short").with_span(record.len(), 1))?
+            }
+        }
+    }
+
+    fn hex(record: &str, start: usize, len: usize) -> Result<u64, Diagnostic> {
+        let field = Self::field(record, start, len)?;
+        u64::from_str_radix(field, 16).map_err(|_| Diagnostic::error(format!("Invalid hex field {}", field)).with_span(start, len))
+    }
+
+    // names are padded to six columns, the last one of a record may have lost its padding
+    fn name(record: &str, start: usize) -> Result<String, Diagnostic> {
+        match record.get(start..(start + 6).min(record.len())) {
+            Some(name) if !name.trim().is_empty() => {
+                Ok(String::from(name.trim_end()))
+            }
+            _ => {
+                Err(Diagnostic::error("Missing symbol name").with_span(start.min(record.len()), 1))?
+            }
+        }
+    }
+
+    // adds one record to the last program, a header record starts a new one
+    fn read_record(programs: &mut Vec<ObjectProgram>, record: &str) -> Result<(), Diagnostic> {
+        let kind = record.chars().next().unwrap();
+
+        if kind == 'H' {
+            let program_name = Self::name(record, 1)?;
+            let start_address = Self::hex(record, 7, 6)?;
+            let len = Self::hex(record, 13, 6)?;
+            programs.push(ObjectProgram{header: HeaderSection{program_name, start_address, len}, defines: vec![], references: vec![], texts: vec![], modifications: vec![], start_address: None});
+            return Ok(());
+        }
+
+        let program = match programs.last_mut() {
+            Some(program) => program,
+            None => Err(Diagnostic::error("Record comes before any header record").with_span(0, 1))?
+        };
+
+        match kind {
+            'D' => {
+                if !(record.len() - 1).is_multiple_of(12) {
+                    Err(Diagnostic::error("Malformed define record").with_span(0, record.len()))?
+                }
+                for start in (1..record.len()).step_by(12) {
+                    program.defines.push((Self::name(record, start)?, Self::hex(record, start + 6, 6)?));
+                }
+            }
+            'R' => {
+                for start in (1..record.len()).step_by(6) {
+                    program.references.push(Self::name(record, start)?);
+                }
+            }
+            'T' => {
+                let address = Self::hex(record, 1, 6)?;
+                let len = Self::hex(record, 7, 2)? as usize;
+                let codes = record.get(9..).unwrap_or("");
+                if codes.len() != len * 2 {
+                    Err(Diagnostic::error(format!("Text record holds {} half-bytes instead of {}", codes.len(), len * 2)).with_span(7, 2))?
+                }
+                let bytes = hex::decode(codes).map_err(|_| Diagnostic::error("Invalid object code").with_span(9, codes.len()))?;
+                program.texts.push(TextRecord{address, bytes});
+            }
+            'M' => {
+                let address = Self::hex(record, 1, 6)?;
+                let len = Self::hex(record, 7, 2)?;
+                let symbol = match record.get(9..10) {
+                    Some(sign) if sign == "+" || sign == "-" => {
+                        Some((sign.chars().next().unwrap(), String::from(&record[10..])))
+                    }
+                    Some(_) => {
+                        Err(Diagnostic::error("Modification record needs a sign before its symbol").with_span(9, 1))?
+                    }
+                    None => {
+                        None
+                    }
+                };
+                program.modifications.push(ModificationSection{address, len, symbol});
+            }
+            'E' => {
+                if record.len() > 1 {
+                    program.start_address = Some(Self::hex(record, 1, 6)?);
+                }
+            }
+            _ => {
+                Err(Diagnostic::error(format!("Unknown record type {}", kind)).with_span(0, 1))?
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn read(source: &str) -> Result<Vec<ObjectProgram>, Box<dyn Error>> {
+        let mut programs: Vec<ObjectProgram> = vec![];
+
+        for (idx, record) in source.lines().enumerate() {
+            let record = record.trim_end();
+
+            if record.is_empty() {
+                continue;
+            }
+
+            Self::read_record(&mut programs, record).map_err(|diagnostic| diagnostic.on_line(idx + 1, record))?;
+        }
+
+        if programs.is_empty() {
+            Err("No header record found")?
+        }
+
+        Ok(programs)
+    }
+
+    // reads the whole file and reports every problem with it instead of stopping at the first one
+    pub fn check(source: &str) -> (Vec<ObjectProgram>, Diagnostics) {
+        let mut programs: Vec<ObjectProgram> = vec![];
+        let mut diagnostics = vec![];
+        // for every program: the lines of its header, text, modification and end records
+        let mut lines: Vec<RecordLines> = vec![];
+
+        for (idx, record) in source.lines().enumerate() {
+            let number = idx + 1;
+            let record = record.trim_end();
+
+            if record.is_empty() {
+                continue;
+            }
+
+            let kind = record.chars().next().unwrap();
+            if kind != 'H' && lines.last().map(|lines| lines.end.is_some()).unwrap_or(false) {
+                diagnostics.push(Diagnostic::error("Record follows the end record of its program").with_span(0, 1).on_line(number, record));
+                continue;
+            }
+
+            match Self::read_record(&mut programs, record) {
+                Ok(()) => {
+                    match kind {
+                        'H' => lines.push(RecordLines{header: (number, String::from(record)), ..RecordLines::default()}),
+                        'T' => lines.last_mut().unwrap().texts.push((number, String::from(record))),
+                        'M' => lines.last_mut().unwrap().modifications.push((number, String::from(record))),
+                        'E' => lines.last_mut().unwrap().end = Some((number, String::from(record))),
+                        _ => {}
+                    }
+                }
+                Err(diagnostic) => {
+                    diagnostics.push(diagnostic.on_line(number, record));
+                    // a broken header would leave the following records to the previous program
+                    if kind == 'H' {
+                        programs.push(ObjectProgram{header: HeaderSection{program_name: String::new(), start_address: 0, len: 0}, defines: vec![], references: vec![], texts: vec![], modifications: vec![], start_address: None});
+                        lines.push(RecordLines{header: (number, String::from(record)), broken: true, ..RecordLines::default()});
+                    }
+                }
+            }
+        }
+
+        if programs.is_empty() {
+            diagnostics.push(Diagnostic::error("No header record found"));
+        }
+
+        let mut names = HashSet::new();
+        for (program, lines) in programs.iter().zip(&lines) {
+            if !lines.broken {
+                Self::check_program(program, lines, &programs, &mut names, &mut diagnostics);
+            }
+        }
+
+        diagnostics.sort_by_key(|diagnostic| diagnostic.line.as_ref().map(|(number, _)| *number));
+        programs.retain(|program| !program.header.program_name.is_empty());
+
+        (programs, diagnostics)
+    }
+
+    fn check_program(program: &ObjectProgram, lines: &RecordLines, programs: &[ObjectProgram], names: &mut HashSet<String>, diagnostics: &mut Diagnostics) {
+        let header = &program.header;
+        let (start, end) = (header.start_address, header.start_address + header.len);
+        let (number, record) = &lines.header;
+
+        if !names.insert(header.program_name.clone()) {
+            diagnostics.push(Diagnostic::error(format!("Duplicate control section {}", header.program_name)).with_span(1, 6).on_line(*number, record));
+        }
+
+        for (name, address) in &program.defines {
+            if *address < start || *address > end {
+                diagnostics.push(Diagnostic::error(format!("Symbol {} at {:06X} lies outside of {}", name, address, header.program_name)).on_line(*number, record));
+            }
+        }
+
+        // text records sorted by address, so that each one only has to be compared to the one before it
+        let mut texts: Vec<(&TextRecord, &(usize, String))> = program.texts.iter().zip(&lines.texts).collect();
+        texts.sort_by_key(|(text, _)| text.address);
+
+        let mut last: Option<(u64, usize)> = None;
+        for (text, (number, record)) in texts {
+            let text_end = text.address + text.bytes.len() as u64;
+
+            if text.bytes.len() > 0x1E {
+                diagnostics.push(Diagnostic::warning(format!("Text record holds {} bytes, more than the 30 a record can take", text.bytes.len())).with_span(7, 2).on_line(*number, record));
+            }
+            if text.address < start || text_end > end {
+                diagnostics.push(Diagnostic::error(format!("Text record {:06X}-{:06X} lies outside of {} ({:06X}-{:06X})", text.address, text_end, header.program_name, start, end)).with_span(1, 6).on_line(*number, record));
+            }
+            if let Some((last_end, last_number)) = last.filter(|(last_end, _)| text.address < *last_end) {
+                diagnostics.push(Diagnostic::error(format!("Text record overlaps the one on line {}", last_number)).with_span(1, 6).on_line(*number, record)
+                    .with_note(format!("that one ends at {:06X}", last_end)));
+            }
+            if last.map(|(last_end, _)| text_end > last_end).unwrap_or(true) {
+                last = Some((text_end, *number));
+            }
+        }
+
+        for (modification, (number, record)) in program.modifications.iter().zip(&lines.modifications) {
+            let size = (modification.len as usize).div_ceil(2) as u64;
+
+            if modification.len == 0 || size > 8 {
+                diagnostics.push(Diagnostic::error(format!("Modification of {} half-bytes", modification.len)).with_span(7, 2).on_line(*number, record));
+            } else if modification.address < start || modification.address + size > end {
+                diagnostics.push(Diagnostic::error(format!("Modification record at {:06X} lies outside of {}", modification.address, header.program_name)).with_span(1, 6).on_line(*number, record));
+            } else if !(modification.address..modification.address + size).all(|address| program.texts.iter().any(|text| text.address <= address && address < text.address + text.bytes.len() as u64)) {
+                diagnostics.push(Diagnostic::warning("Modification record changes bytes no text record writes").with_span(1, 6).on_line(*number, record));
+            }
+
+            // an external symbol has to come from a refer record, or be the section itself
+            if let Some((_, name)) = &modification.symbol {
+                let known = *name == header.program_name || program.references.contains(name) || program.defines.iter().any(|(define, _)| define == name);
+                if !known {
+                    diagnostics.push(Diagnostic::error(format!("Symbol {} is neither the section nor in its refer records", name)).with_span(10, name.len()).on_line(*number, record));
+                } else if !programs.iter().any(|other| other.header.program_name == *name || other.defines.iter().any(|(define, _)| define == name)) {
+                    diagnostics.push(Diagnostic::warning(format!("Symbol {} is not defined in this file", name)).with_span(10, name.len()).on_line(*number, record)
+                        .with_note("it has to come from another object file when the program is linked"));
+                }
+            }
+        }
+
+        match (&lines.end, program.start_address) {
+            (None, _) => {
+                diagnostics.push(Diagnostic::error(format!("{} has no end record", header.program_name)).on_line(*number, record));
+            }
+            (Some((number, record)), Some(entry)) if entry < start || entry >= end.max(start + 1) => {
+                diagnostics.push(Diagnostic::error(format!("Entry point {:06X} lies outside of {}", entry, header.program_name)).with_span(1, 6).on_line(*number, record));
+            }
+            _ => {}
+        }
+    }
+}
+
+#[derive(Default)]
+struct RecordLines {
+    header: (usize, String),
+    texts: Vec<(usize, String)>,
+    modifications: Vec<(usize, String)>,
+    end: Option<(usize, String)>,
+    // the header could not be read, so nothing else is checked
+    broken: bool
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostic::Severity;
+    use crate::{assemble, Options};
+
+    fn problems(source: &str) -> Vec<(usize, Severity, String)> {
+        let (_, diagnostics) = ObjectProgram::check(source);
+        diagnostics.into_iter().map(|diagnostic| (diagnostic.line.unwrap().0, diagnostic.severity, diagnostic.message)).collect()
+    }
+
+    #[test]
+    fn assembled_programs_pass() {
+        let program = assemble(include_str!("../tests/programs/csect.asm"), &Options::default()).unwrap();
+        let (programs, diagnostics) = ObjectProgram::check(&program.records());
+
+        assert!(diagnostics.is_empty());
+        assert_eq!(programs.len(), 3);
+    }
+
+    #[test]
+    fn every_problem_is_reported() {
+        let source = "HPROG  000000000010
+DSYM   000020
+T000000044B100000
+T0000020300000000
+T00002003000000
+M00000105+OTHER
+M00000A05
+E000020
+T00000003000000
+";
+        assert_eq!(problems(source), [
+            (1, Severity::Error, String::from("Symbol SYM at 000020 lies outside of PROG")),
+            (4, Severity::Error, String::from("Text record holds 8 half-bytes instead of 6")),
+            (5, Severity::Error, String::from("Text record 000020-000023 lies outside of PROG (000000-000010)")),
+            (6, Severity::Error, String::from("Symbol OTHER is neither the section nor in its refer records")),
+            (7, Severity::Warning, String::from("Modification record changes bytes no text record writes")),
+            (8, Severity::Error, String::from("Entry point 000020 lies outside of PROG")),
+            (9, Severity::Error, String::from("Record follows the end record of its program"))
+        ]);
+    }
+
+    #[test]
+    fn text_records_must_not_overlap() {
+        let source = "HPROG  000000000010
+T00000003000000
+T00000203000000
+E
+";
+        assert_eq!(problems(source), [(3, Severity::Error, String::from("Text record overlaps the one on line 2"))]);
+    }
+}
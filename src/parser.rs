@@ -1,18 +1,43 @@
 mod token;
 pub mod expression;
 pub mod command;
+pub mod term;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fmt::{Display, Formatter};
-use std::fs::File;
-use std::io::{BufRead, BufReader};
+use crate::diagnostic::{Diagnostic, Diagnostics};
+use crate::lexer::{self, SourceFormat};
+use crate::macros::{LineKind, MacroProcessor, SourceLine};
+use crate::object::{ObjectProgram, TextRecord};
+use crate::simulator::MEMORY_SIZE;
+use crate::Options;
 use expression::*;
 use command::*;
 use token::*;
+use term::*;
 
 
-// TODO: Check RSUB
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SymbolKind {
+    Absolute,
+    Relative,
+    External
+}
+
+#[derive(Copy, Clone)]
+pub struct Symbol {
+    pub value: u64,
+    pub kind: SymbolKind,
+    pub block: usize
+}
+
+pub type SymbolTable = HashMap<String, Symbol>;
+
+// (program block, address)
+pub type Location = (usize, u64);
+
+#[derive(Clone)]
 pub struct HeaderSection {
     pub program_name: String,
     pub start_address: u64,
@@ -29,10 +54,15 @@ impl HeaderSection {
     pub fn from_expression(expression: &Expression) -> Result<Self, &str> {
         if let (Command::Directive(Directive::START), _) = expression.command {
             if let Some((Operand::Literal(Literal::Integer(start_addr)), _)) = expression.operand {
-                Ok(Self{program_name: String::from(expression.label.as_ref().unwrap()), start_address: u64::from_str_radix(&(start_addr).to_string(), 16).unwrap(), len: 0})
+                match u64::from_str_radix(&(start_addr).to_string(), 16) {
+                    Ok(start_address) => Ok(Self{program_name: String::from(expression.label.as_ref().unwrap()), start_address, len: 0}),
+                    Err(_) => Err("Invalid start address")
+                }
             } else {
                 Err("Invalid Literal")
             }
+        } else if let (Command::Directive(Directive::CSECT), _) = expression.command {
+            Ok(Self{program_name: String::from(expression.label.as_ref().unwrap()), start_address: 0, len: 0})
         } else {
             Err("Not start directive")
         }
@@ -55,27 +85,78 @@ impl Display for TextSection {
     }
 }
 
+pub struct DefineSection {
+    pub symbols: Vec<(String, u64)>
+}
+
+impl Display for DefineSection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut str = String::new();
+        for (name, address) in &self.symbols {
+            str += &format!("{:6}{:06X}", name, address);
+        }
+        write!(f, "D{}", str)
+    }
+}
+
+pub struct ReferSection {
+    pub symbols: Vec<String>
+}
+
+impl Display for ReferSection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut str = String::new();
+        for name in &self.symbols {
+            str += &format!("{:6}", name);
+        }
+        write!(f, "R{}", str)
+    }
+}
+
+#[derive(Clone)]
+pub struct ModificationSection {
+    pub address: u64,
+    // length of the modified field in half-bytes
+    pub len: u64,
+    pub symbol: Option<(char, String)>
+}
+
+impl Display for ModificationSection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "M{:06X}{:02X}", self.address, self.len)?;
+        if let Some((sign, name)) = &self.symbol {
+            write!(f, "{}{}", sign, name)?;
+        }
+        Ok(())
+    }
+}
+
 pub struct EndSection {
-    start_address: u64,
-    pub start_label: String
+    pub start_address: Option<u64>
 }
 
 impl Display for EndSection {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "E{:06X}", self.start_address)
+        if let Some(start_address) = self.start_address {
+            write!(f, "E{:06X}", start_address)
+        } else {
+            write!(f, "E")
+        }
     }
 }
 
 impl EndSection {
-    pub fn from_expression(expression: &Expression, symbol_table: &HashMap<String, u64>) -> Result<Self, Box<dyn Error>> {
+    pub fn from_expression(expression: &Expression, symbol_table: &SymbolTable) -> Result<Self, Box<dyn Error>> {
         if let (Command::Directive(Directive::END), _) = expression.command {
-            if let Some((Operand::Symbol(symbol), _)) = &expression.operand {
+            if let Some((Operand::Term(Term::Symbol(symbol)), _)) = &expression.operand {
                 if let Some(addr) = symbol_table.get(symbol){
-                    Ok(Self{start_address: *addr, start_label: symbol.to_string()})
+                    Ok(Self{start_address: Some(addr.value)})
                 } else {
                     Err(format!("Symbol: {} not found", symbol))?
                 }
 
+            } else if expression.operand.is_none() {
+                Ok(Self{start_address: None})
             } else {
                 Err("Invalid Symbol in end text")?
             }
@@ -86,262 +167,1233 @@ impl EndSection {
 }
 
 
-pub struct ParserData {
-    pub symbol_table: HashMap<String, u64>,
+pub struct Block {
+    pub name: String,
+    pub start_address: u64,
+    pub len: u64
+}
+
+// an operand value whose relative terms are counted per program block, so that
+// pairs such as BUFEND-BUFFER cancel out into an absolute value
+struct Evaluation {
+    value: i64,
+    relatives: Vec<(usize, i64)>,
+    externals: Vec<(char, String)>
+}
+
+impl Evaluation {
+    fn from_symbol(symbol: &Symbol) -> Self {
+        if symbol.kind == SymbolKind::Relative {
+            Self{value: symbol.value as i64, relatives: vec![(symbol.block, 1)], externals: vec![]}
+        } else {
+            Self{value: symbol.value as i64, relatives: vec![], externals: vec![]}
+        }
+    }
+
+    fn negate(self: Self) -> Result<Self, Box<dyn Error>> {
+        let relatives = self.relatives.into_iter().map(|(block, count)| (block, -count)).collect();
+        let externals = self.externals.into_iter().map(|(sign, name)| (if sign == '+' { '-' } else { '+' }, name)).collect();
+        let value = self.value.checked_neg().ok_or("Arithmetic overflow in expression")?;
+        Ok(Self{value, relatives, externals})
+    }
+
+    fn add(mut self: Self, other: Self) -> Result<Self, Box<dyn Error>> {
+        for (block, count) in other.relatives {
+            if let Some(idx) = self.relatives.iter().position(|(b, _)| *b == block) {
+                self.relatives[idx].1 += count;
+            } else {
+                self.relatives.push((block, count));
+            }
+        }
+        self.relatives.retain(|(_, count)| *count != 0);
+        self.externals.extend(other.externals);
+        self.value = self.value.checked_add(other.value).ok_or("Arithmetic overflow in expression")?;
+        Ok(self)
+    }
+
+    fn is_absolute(self: &Self) -> bool {
+        self.relatives.is_empty() && self.externals.is_empty()
+    }
+
+    // the part of the value that stays in this section: absolute or relative to one block
+    fn symbol(self: &Self) -> Result<Symbol, Box<dyn Error>> {
+        // a word holds 24 bits, negative values are stored as two's complement
+        if self.value < -(1 << 23) || self.value >= 1 << 24 {
+            Err(format!("Value {} does not fit in a 24-bit word", self.value))?
+        }
+
+        match self.relatives.as_slice() {
+            [] => {
+                Ok(Symbol{value: self.value as u64, kind: SymbolKind::Absolute, block: 0})
+            }
+            [(block, 1)] => {
+                Ok(Symbol{value: self.value as u64, kind: SymbolKind::Relative, block: *block})
+            }
+            _ => {
+                Err("Illegal combination of relative terms in expression")?
+            }
+        }
+    }
+}
+
+// result of the first pass
+struct Layout {
+    symbol_table: SymbolTable,
+    locations: Vec<Location>,
+    blocks: Vec<Block>
+}
+
+pub struct ListingLine {
+    // index into ParserData::source
+    pub source: usize,
+    pub address: u64,
+    pub block: usize,
+    pub expression: Option<Expression>,
+    pub opcode: String
+}
+
+pub struct ControlSection {
+    pub symbol_table: SymbolTable,
+    pub blocks: Vec<Block>,
     pub header: HeaderSection,
+    pub defines: Vec<DefineSection>,
+    pub references: Vec<ReferSection>,
     pub texts: Vec<TextSection>,
+    pub modifications: Vec<ModificationSection>,
     pub end: EndSection
 }
 
+pub struct ParserData {
+    pub sections: Vec<ControlSection>,
+    pub listing: Vec<ListingLine>,
+    pub source: Vec<SourceLine>
+}
+
 impl ParserData {
 
-    fn get_addr(addr: u64, pc: u64, base: &Option<u64>) -> (i64, Option<Flag>){
+    // the displacement and the flag that reach addr, if PC or base relative addressing can
+    fn get_addr(addr: u64, pc: u64, base: &Option<u64>) -> Option<(i64, Flag)> {
         let addr = addr as i64;
         let pc = pc as i64;
 
         if (addr - pc) >= -2048 && (addr - pc) <= 2047  {
-            (addr - pc, Some(Flag::P))
+            Some((addr - pc, Flag::P))
         } else if let Some(base) = *base {
             let base = base as i64;
             if (addr - base) >= 0 && (addr - base) <= 4095 {
-                (addr - base, Some(Flag::B))
+                Some((addr - base, Flag::B))
             } else {
-                (addr, None)
+                None
             }
 
         } else {
-            (addr, None)
+            None
         }
     }
 
-    fn get_symbols(expressions: &Vec<Expression>, start_address: u64) -> Result<HashMap<String, u64>, &str> {
-        let mut symbol_table = HashMap::new();
-        let mut addr = start_address;
+    // a value that goes into the address field of a format 3 or 4 instruction as it is
+    fn absolute_field(expression: &Expression, value: i64) -> Result<u32, Diagnostic> {
+        let bits = if expression.stat.is_set(Flag::E) { 20 } else { 12 };
 
-        for expression in expressions {
-            if let Some(label) = &expression.label {
-                match symbol_table.get(label) {
-                    Some(_) => {
-                        return Err("Duplicated Symbol");
+        if value < 0 || value >= 1 << bits {
+            let mut diagnostic = expression.operand_error(format!("Value {} does not fit in the {}-bit address field", value, bits));
+            if bits == 12 && (0..1 << 20).contains(&value) {
+                diagnostic = diagnostic.with_note(format!("use format 4 (+{}) or assemble with --relax", expression.command.1));
+            }
+            Err(diagnostic)?
+        }
+
+        Ok(value as u32)
+    }
+
+    fn evaluate_term(term: &Term, symbol_table: &SymbolTable, location: Symbol) -> Result<Evaluation, Box<dyn Error>> {
+        match term {
+            Term::Integer(num) => {
+                Ok(Evaluation{value: *num, relatives: vec![], externals: vec![]})
+            }
+            Term::Symbol(name) => {
+                match symbol_table.get(name) {
+                    Some(symbol) if symbol.kind == SymbolKind::External => {
+                        Ok(Evaluation{value: 0, relatives: vec![], externals: vec![('+', name.clone())]})
+                    }
+                    Some(symbol) => {
+                        Ok(Evaluation::from_symbol(symbol))
                     }
                     None => {
-                        symbol_table.insert(String::from(label), addr);
+                        Err(format!("Symbol {} not found", name))?
+                    }
+                }
+            }
+            Term::LocationCounter => {
+                Ok(Evaluation::from_symbol(&location))
+            }
+            Term::Negate(term) => {
+                Self::evaluate_term(term, symbol_table, location)?.negate()
+            }
+            Term::Binary(lhs, operator, rhs) => {
+                let lhs = Self::evaluate_term(lhs, symbol_table, location)?;
+                let rhs = Self::evaluate_term(rhs, symbol_table, location)?;
+
+                match operator {
+                    Operator::Add => {
+                        lhs.add(rhs)
+                    }
+                    Operator::Sub => {
+                        lhs.add(rhs.negate()?)
+                    }
+                    Operator::Mul | Operator::Div => {
+                        if !lhs.is_absolute() || !rhs.is_absolute() {
+                            Err("Relative or external terms cannot be multiplied or divided")?
+                        }
+                        let value = if *operator == Operator::Mul {
+                            lhs.value.checked_mul(rhs.value)
+                        } else if rhs.value == 0 {
+                            Err("Division by zero in expression")?
+                        } else {
+                            lhs.value.checked_div(rhs.value)
+                        };
+                        match value {
+                            Some(value) => Ok(Evaluation{value, relatives: vec![], externals: vec![]}),
+                            None => Err("Arithmetic overflow in expression")?
+                        }
                     }
                 }
             }
-            addr += expression.len() as u64;
+        }
+    }
+
+    fn evaluate(operand: &Operand, symbol_table: &SymbolTable, location: Symbol) -> Result<Evaluation, Box<dyn Error>> {
+        match operand {
+            Operand::Literal(Literal::Integer(num)) => {
+                Ok(Evaluation{value: *num as i64, relatives: vec![], externals: vec![]})
+            }
+            Operand::Term(term) => {
+                Self::evaluate_term(term, symbol_table, location)
+            }
+            _ => {
+                Err("Invalid expression")?
+            }
+        }
+    }
+
+    // for operands that have to be known while assembling, e.g. EQU, ORG and BASE
+    fn evaluate_local(operand: &Operand, symbol_table: &SymbolTable, location: Symbol) -> Result<Symbol, Box<dyn Error>> {
+        let evaluation = Self::evaluate(operand, symbol_table, location)?;
+
+        if let Some((_, name)) = evaluation.externals.first() {
+            Err(format!("External symbol {} cannot be used here", name))?
         }
 
-        Ok(symbol_table)
+        evaluation.symbol()
     }
 
-    fn parse(expressions: &Vec<Expression>, symbol_table: &HashMap<String, u64>, start_address: u64) -> Result<Vec<String>, Box<dyn Error>>{
+    fn place_literals(expressions: Vec<Expression>) -> Result<Vec<Expression>, Diagnostic> {
         let mut res = vec![];
-        let mut base = None;
-        let mut pc = start_address;
+        let mut pending: Vec<String> = vec![];
+        let mut placed = HashSet::new();
+
+        for expression in expressions {
+            if let Some((Operand::Term(Term::Symbol(name)), _)) = &expression.operand {
+                if name.starts_with('=') && !placed.contains(name) {
+                    placed.insert(name.clone());
+                    pending.push(name.clone());
+                }
+            }
+
+            // the pool is dumped after LTORG, or right before CSECT/END for whatever is still pending
+            match expression.command.0 {
+                Command::Directive(Directive::LTORG) => {
+                    let source = expression.source;
+                    res.push(expression);
+                    for name in pending.drain(..) {
+                        let mut literal = Expression::from_literal(&name).map_err(|err| Diagnostic::from(err).at(source))?;
+                        literal.source = source;
+                        res.push(literal);
+                    }
+                }
+                Command::Directive(Directive::END) | Command::Directive(Directive::CSECT) => {
+                    for name in pending.drain(..) {
+                        let mut literal = Expression::from_literal(&name).map_err(|err| expression.command_error(err))?;
+                        literal.source = expression.source;
+                        res.push(literal);
+                    }
+                    // a control section cannot address the pools of another one
+                    placed.clear();
+                    res.push(expression);
+                }
+                _ => {
+                    res.push(expression);
+                }
+            }
+        }
+
+        Ok(res)
+    }
+
+    // the symbols a statement defines, and where ORG moves the location counter to
+    fn define(expression: &Expression, symbol_table: &mut SymbolTable, origins: &mut Vec<Location>, counters: &mut [u64], cur: usize, start_address: u64) -> Result<(), Diagnostic> {
+        let location = Symbol{value: counters[cur], kind: SymbolKind::Relative, block: cur};
+        let operand_error = |err: Box<dyn Error>| expression.operand_error(err);
+
+        if let Some(label) = &expression.label {
+            if symbol_table.contains_key(label) {
+                Err(expression.label_error(format!("Duplicated Symbol {}", label)))?
+            }
+
+            // EQU binds the label to its operand instead of the location counter
+            let symbol = if let (Command::Directive(Directive::EQU), _) = expression.command {
+                Self::evaluate_local(&expression.operand.as_ref().unwrap().0, symbol_table, location).map_err(operand_error)?
+            } else {
+                location
+            };
+
+            symbol_table.insert(String::from(label), symbol);
+        }
 
-        for expression in &expressions[1..expressions.len() - 1] {
-            pc += expression.len() as u64;
+        // external symbols are only known by name until the linker resolves them
+        if let (Command::Directive(Directive::EXTREF), _) = expression.command {
+            for name in expression.symbols() {
+                if symbol_table.contains_key(&name) {
+                    Err(expression.operand_error(format!("Duplicated Symbol {}", name)))?
+                }
+                symbol_table.insert(name, Symbol{value: 0, kind: SymbolKind::External, block: 0});
+            }
+        }
 
-            match &expression.command.0 {
-                Command::Directive(directive) => {
-                    match directive {
-                        Directive::RESB => {
-                            res.push("".to_string());
+        if let (Command::Directive(Directive::ORG), _) = expression.command {
+            match &expression.operand {
+                Some((operand, _)) => {
+                    // the location counter is needed right away, so later definitions cannot be used
+                    let symbol = Self::evaluate_local(operand, symbol_table, location).map_err(|err| {
+                        expression.operand_error(format!("Cannot evaluate the ORG operand: {}", err))
+                            .with_note("ORG can only use symbols defined before it")
+                    })?;
+                    origins.push((cur, counters[cur]));
+
+                    if symbol.kind == SymbolKind::Absolute {
+                        if cur != 0 {
+                            Err(expression.operand_error(format!("ORG to the absolute address {:X} is only allowed in the default block", symbol.value))
+                                .with_note("use a label of the current block to move within it"))?
                         }
-                        Directive::RESW => {
-                            res.push("".to_string());
+                        if symbol.value < start_address {
+                            Err(expression.operand_error(format!("ORG address {:X} lies before the start of the program at {:X}", symbol.value, start_address)))?
                         }
-                        Directive::BYTE => {
-                            match &expression.operand.as_ref().unwrap().0 {
-                                Operand::Literal(literal) => {
-                                    match literal {
-                                        Literal::Integer(num) => {
-                                            res.push(format!("{:02X}", num));
-                                        }
-                                        Literal::String(str) => {
-                                            res.push(hex::encode_upper(str));
-                                        }
-                                        _ => {
-                                            Err("Invalid expression")?
-                                        }
+                        counters[cur] = symbol.value - start_address;
+                    } else if symbol.kind == SymbolKind::Relative && symbol.block == cur {
+                        counters[cur] = symbol.value;
+                    } else {
+                        Err(expression.operand_error("ORG target must be in the current program block"))?
+                    }
+                }
+                None => {
+                    if let Some((block, origin)) = origins.pop() {
+                        counters[block] = origin;
+                    } else {
+                        Err(expression.command_error("ORG without operand must follow an ORG with operand"))?
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn get_symbols(expressions: &[Expression], start_address: u64, diagnostics: &mut Diagnostics) -> Layout {
+        let mut symbol_table = SymbolTable::new();
+        let mut locations = vec![];
+        let mut origins = vec![];
+        // every block counts from zero until its start address is known at the end of the pass
+        let mut blocks = vec![Block{name: String::new(), start_address, len: 0}];
+        let mut counters = vec![0u64];
+        let mut cur = 0;
+        let mut overflowed = false;
+
+        for expression in expressions {
+            // USE is listed at the location of the block it switches to
+            if let (Command::Directive(Directive::USE), _) = expression.command {
+                let name = match &expression.operand {
+                    Some((Operand::Term(Term::Symbol(name)), _)) => name.clone(),
+                    _ => String::new()
+                };
+
+                cur = match blocks.iter().position(|block| block.name == name) {
+                    Some(idx) => idx,
+                    None => {
+                        blocks.push(Block{name, start_address: 0, len: 0});
+                        counters.push(0);
+                        blocks.len() - 1
+                    }
+                };
+            }
+
+            locations.push((cur, counters[cur]));
+
+            if let Err(diagnostic) = Self::define(expression, &mut symbol_table, &mut origins, &mut counters, cur, start_address) {
+                diagnostics.push(diagnostic);
+            }
+
+            counters[cur] += expression.len() as u64;
+            blocks[cur].len = blocks[cur].len.max(counters[cur]);
+
+            // the blocks end up one after another, so together they must fit in memory
+            let end = start_address + blocks.iter().map(|block| block.len).sum::<u64>();
+            if end > MEMORY_SIZE as u64 && !overflowed {
+                diagnostics.push(expression.command_error(format!("The program does not fit in the {} byte address space", MEMORY_SIZE))
+                    .with_note(format!("it would end at {:X}", end)));
+                overflowed = true;
+            }
+        }
+
+        // lay the blocks out one after another and relocate everything defined inside them
+        for idx in 1..blocks.len() {
+            blocks[idx].start_address = blocks[idx - 1].start_address + blocks[idx - 1].len;
+        }
+
+        for symbol in symbol_table.values_mut() {
+            if symbol.kind == SymbolKind::Relative {
+                symbol.value += blocks[symbol.block].start_address;
+            }
+        }
+
+        let locations = locations.into_iter()
+            .map(|(block, offset)| (block, blocks[block].start_address + offset))
+            .collect();
+
+        Layout{symbol_table, locations, blocks}
+    }
+
+    // the object code of one statement, pushed onto res
+    fn parse_statement(expression: &Expression, (block, address): Location, symbol_table: &SymbolTable, mode: Mode, base: &mut Option<u64>, res: &mut Vec<String>, modifications: &mut Vec<ModificationSection>) -> Result<(), Diagnostic> {
+        let location = Symbol{value: address, kind: SymbolKind::Relative, block};
+        let pc = address + expression.len() as u64;
+        let operand_error = |err: Box<dyn Error>| expression.operand_error(err);
+
+        match &expression.command.0 {
+            Command::Directive(directive) => {
+                match directive {
+                    Directive::RESB => {
+                        res.push("".to_string());
+                    }
+                    Directive::RESW => {
+                        res.push("".to_string());
+                    }
+                    Directive::BYTE => {
+                        match &expression.operand.as_ref().unwrap().0 {
+                            Operand::Literal(literal) => {
+                                match literal {
+                                    Literal::Integer(num) => {
+                                        res.push(format!("{:01$X}", num, expression.len() * 2));
+                                    }
+                                    Literal::String(str) => {
+                                        res.push(hex::encode_upper(str));
+                                    }
+                                    _ => {
+                                        Err(expression.operand_error("Invalid expression"))?
                                     }
                                 }
-                                Operand::Symbol(_) => {
-                                    return Err("Invalid expression")?
-                                }
+                            }
+                            Operand::Term(_) | Operand::SymbolList(_) => {
+                                Err(expression.operand_error("Invalid expression"))?
                             }
                         }
-                        Directive::WORD => {
-                            match &expression.operand.as_ref().unwrap().0 {
-                                Operand::Literal(literal) => {
-                                    match literal {
-                                        Literal::Integer(num) => {
-                                            res.push(format!("{:06X}", num));
-                                        }
-                                        _ => {
-                                            Err("Invalid expression")?
+                    }
+                    Directive::WORD => {
+                        match &expression.operand.as_ref().unwrap().0 {
+                            Operand::Literal(literal) => {
+                                match literal {
+                                    Literal::Integer(num) => {
+                                        if *num < -(1 << 23) || *num >= 1 << 24 {
+                                            Err(expression.operand_error(format!("Value {} does not fit in a 24-bit word", num)))?
                                         }
+                                        res.push(format!("{:06X}", num & 0xFFFFFF));
+                                    }
+                                    _ => {
+                                        Err(expression.operand_error("Invalid expression"))?
                                     }
-                                }
-                                Operand::Symbol(_) => {
-                                    Err("Invalid expression")?
                                 }
                             }
-                        }
-                        Directive::BASE => {
-                            match &expression.operand.as_ref().unwrap().0 {
-                                Operand::Symbol(symbol) => {
-                                    if let Some(addr) = symbol_table.get(symbol) {
-                                        base = Some(*addr);
-                                        res.push("".to_string());
-                                    } else {
-                                        Err(format!("Symbol {} not found", symbol))?
-                                    }
+                            Operand::Term(_) => {
+                                let evaluation = Self::evaluate(&expression.operand.as_ref().unwrap().0, symbol_table, location).map_err(operand_error)?;
+                                let symbol = evaluation.symbol().map_err(operand_error)?;
+                                if symbol.kind == SymbolKind::Relative {
+                                    modifications.push(ModificationSection{address, len: 6, symbol: None});
                                 }
-                                Operand::Literal(_) => {
-                                    Err("Invalid expression")?
+                                for (sign, name) in evaluation.externals {
+                                    modifications.push(ModificationSection{address, len: 6, symbol: Some((sign, name))});
                                 }
+                                res.push(format!("{:06X}", symbol.value & 0xFFFFFF));
+                            }
+                            Operand::SymbolList(_) => {
+                                Err(expression.operand_error("Invalid expression"))?
                             }
                         }
-                        _ => {
-                            Err("Start and End cannot be in the text segment")?
+                    }
+                    Directive::BASE => {
+                        match &expression.operand.as_ref().unwrap().0 {
+                            Operand::Term(_) => {
+                                *base = Some(Self::evaluate_local(&expression.operand.as_ref().unwrap().0, symbol_table, location).map_err(operand_error)?.value);
+                                res.push("".to_string());
+                            }
+                            Operand::Literal(_) | Operand::SymbolList(_) => {
+                                Err(expression.operand_error("Invalid expression"))?
+                            }
                         }
                     }
+                    Directive::EQU | Directive::ORG | Directive::LTORG | Directive::USE => {
+                        res.push("".to_string());
+                    }
+                    Directive::START | Directive::END | Directive::CSECT | Directive::EXTDEF | Directive::EXTREF => {
+                        res.push("".to_string());
+                    }
                 }
-                Command::Mnemonic(mnemonic) => {
-                    let mut code: u32 = 0;
-                    let opcode = mnemonic.opcode as u32;
-                    match mnemonic.format {
-                        Format::ONE => {
-                            code |= opcode << 16;
-                            res.push(format!("{: <06X}", code));
+            }
+            Command::Mnemonic(mnemonic) => {
+                let mut code: u32 = 0;
+                let opcode = mnemonic.opcode as u32;
+                match mnemonic.format {
+                    Format::ONE => {
+                        code |= opcode;
+                        res.push(format!("{:02X}", code));
+                    }
+                    Format::TWO => {
+                        code |= opcode << 8;
+                        if let Operand::Literal(Literal::RegisterPair((r1, r2))) = expression.operand.as_ref().unwrap().0 {
+                            code |= ((r1 as u32) << 4) | (r2 as u32);
+                            res.push(format!("{: <04X}", code));
+                        } else {
+                            Err(expression.operand_error("Invalid expression"))?
                         }
-                        Format::TWO => {
-                            code |= opcode << 8;
-                            if let Operand::Literal(Literal::RegisterPair((r1, r2))) = expression.operand.as_ref().unwrap().0 {
-                                code |= ((r1 as u32) << 4) | (r2 as u32);
-                                res.push(format!("{: <04X}", code));
-                            } else {
-                                Err("Invalid expression")?
+                    }
+                    Format::ThreeAndFour if mode == Mode::SIC => {
+                        // opcode, x bit and a 15-bit direct address
+                        code |= opcode << 16;
+                        if expression.stat.is_set(Flag::X) {
+                            code |= 1 << 15;
+                        }
+                        let addr = match &expression.operand {
+                            None => {
+                                // RSUB
+                                0
+                            }
+                            Some((operand @ Operand::Term(_), _)) => {
+                                let evaluation = Self::evaluate(operand, symbol_table, location).map_err(operand_error)?;
+                                let symbol = evaluation.symbol().map_err(operand_error)?;
+                                if symbol.kind == SymbolKind::Relative {
+                                    modifications.push(ModificationSection{address: address + 1, len: 4, symbol: None});
+                                }
+                                for (sign, name) in evaluation.externals {
+                                    modifications.push(ModificationSection{address: address + 1, len: 4, symbol: Some((sign, name))});
+                                }
+                                symbol.value
+                            }
+                            Some((Operand::Literal(Literal::Integer(num)), _)) => {
+                                *num as u64
                             }
+                            _ => {
+                                Err(expression.operand_error("Invalid expression"))?
+                            }
+                        };
+                        if addr > 0x7FFF {
+                            Err(expression.operand_error(format!("Address {:X} does not fit in 15 bits", addr)))?
+                        }
+                        code |= addr as u32;
+                        res.push(format!("{:06X}", code));
+                    }
+                    Format::ThreeAndFour => {
+                        if expression.stat.is_set(Flag::E) {
+                            code |= (opcode & 0xFC) << 24;
+                        } else {
+                            code |= (opcode & 0xFC) << 16;
                         }
-                        Format::ThreeAndFour => {
-                            if expression.stat.is_set(Flag::E) {
-                                code |= (opcode & 0xFC) << 24;
-                            } else {
-                                code |= (opcode & 0xFC) << 16;
+                        let mut stat = expression.stat;
+                        let mut addr = 0u32;
+                        match &expression.operand {
+                            None => {
+                                // RSUB has nothing to address
                             }
-                            let mut stat = expression.stat;
-                            let mut addr = 0u32;
-                            match &expression.operand.as_ref().unwrap().0 {
-                                Operand::Symbol(symbol) => {
-                                    if let Some(address) = symbol_table.get(symbol) {
-                                        if expression.stat.is_set(Flag::E) {
-                                            addr = (((*address) as u32) & ((1u32 << 20) - 1u32)) as u32;
-                                        } else {
-                                            let (bias, flag) = Self::get_addr(*address, pc, &base);
-                                            if let Some(flag) = flag {
-                                                stat.set(flag);
-                                                addr = (bias as u32) & ((1u32 << 12) - 1) as u32;
-                                            } else {
-                                                addr = (bias as u32) & ((1u32 << 12) - 1) as u32;
-                                            }
-                                        }
-                                    } else {
-                                        Err(format!("Symbol {} not found", symbol))?
+                            Some((operand @ Operand::Term(_), _)) => {
+                                let evaluation = Self::evaluate(operand, symbol_table, location).map_err(operand_error)?;
+                                let symbol = evaluation.symbol().map_err(operand_error)?;
+
+                                if !evaluation.externals.is_empty() {
+                                    if !expression.stat.is_set(Flag::E) {
+                                        Err(expression.operand_error(format!("External symbol {} requires format 4", evaluation.externals[0].1)))?
+                                    }
+                                    // the linker adds the external addresses to whatever is assembled in place
+                                    for (sign, name) in &evaluation.externals {
+                                        modifications.push(ModificationSection{address: address + 1, len: 5, symbol: Some((*sign, name.clone()))});
                                     }
                                 }
-                                Operand::Literal(literal) => {
-                                    match literal {
-                                        Literal::Integer(num) => {
-                                            addr = *num as u32;
+
+                                if symbol.kind == SymbolKind::Absolute && !evaluation.externals.is_empty() {
+                                    // the linker adds the external addresses, so a negative constant part is fine
+                                    if evaluation.value <= -(1 << 20) || evaluation.value >= 1 << 20 {
+                                        Err(expression.operand_error(format!("Value {} does not fit in the 20-bit address field", evaluation.value)))?
+                                    }
+                                    addr = (evaluation.value as u32) & ((1u32 << 20) - 1u32);
+                                } else if symbol.kind == SymbolKind::Absolute {
+                                    // absolute values (e.g. from EQU) are used as-is like an integer literal
+                                    addr = Self::absolute_field(expression, evaluation.value)?;
+                                } else if expression.stat.is_set(Flag::E) {
+                                    // a direct 20-bit address moves with the program
+                                    modifications.push(ModificationSection{address: address + 1, len: 5, symbol: None});
+                                    addr = Self::absolute_field(expression, evaluation.value)?;
+                                } else {
+                                    match Self::get_addr(symbol.value, pc, base) {
+                                        Some((bias, flag)) => {
+                                            stat.set(flag);
+                                            addr = (bias as u32) & ((1u32 << 12) - 1);
                                         }
-                                        _ => {
-                                            Err("Invalid expression")?
+                                        None => {
+                                            Err(expression.operand_error(format!("Address {:X} is out of range for PC relative and base relative addressing", symbol.value))
+                                                .with_note(format!("use format 4 (+{}) or assemble with --relax", expression.command.1)))?
                                         }
                                     }
                                 }
                             }
-                            if expression.stat.is_set(Flag::E) {
-                                code |= (stat.get_val() as u32) << 20;
-                                code |= addr;
-                                res.push(format!("{: <08X}", code));
-                            } else {
-                                code |= (stat.get_val() as u32) << 12;
-                                code |= addr;
-                                res.push(format!("{: <06X}", code));
+                            Some((Operand::Literal(literal), _)) => {
+                                match literal {
+                                    Literal::Integer(num) => {
+                                        addr = Self::absolute_field(expression, *num as i64)?;
+                                    }
+                                    _ => {
+                                        Err(expression.operand_error("Invalid expression"))?
+                                    }
+                                }
+                            }
+                            Some((Operand::SymbolList(_), _)) => {
+                                Err(expression.operand_error("Invalid expression"))?
                             }
-
                         }
-                    }
+                        if expression.stat.is_set(Flag::E) {
+                            code |= (stat.get_val() as u32) << 20;
+                            code |= addr;
+                            res.push(format!("{: <08X}", code));
+                        } else {
+                            code |= (stat.get_val() as u32) << 12;
+                            code |= addr;
+                            res.push(format!("{: <06X}", code));
+                        }
 
+                    }
                 }
+
             }
         }
-        Ok(res)
+        Ok(())
     }
 
-    pub fn from_file(file: File) -> Result<Self, &'static str> {
-        let mut expressions = vec![];
-        let mut line_cnt = 1;
+    // an absolute value too wide for the 12-bit field of format 3 that fits in the 20 bits of format 4
+    fn needs_extension(value: i64) -> bool {
+        (1 << 12..1 << 20).contains(&value)
+    }
+
+    // format 3 statements whose operand neither PC nor base relative addressing reaches, or that refer to
+    // an external symbol; anything that does not evaluate is left for parse to report
+    fn far_statements(expressions: &[Expression], symbol_table: &SymbolTable, locations: &[Location]) -> Vec<usize> {
+        let mut res = vec![];
+        let mut base = None;
+
+        for (idx, (expression, &(block, address))) in expressions.iter().zip(locations).enumerate() {
+            let location = Symbol{value: address, kind: SymbolKind::Relative, block};
+
+            match (&expression.command.0, &expression.operand) {
+                (Command::Directive(Directive::BASE), Some((operand, _))) => {
+                    base = Self::evaluate_local(operand, symbol_table, location).ok().map(|symbol| symbol.value);
+                }
+                (Command::Mnemonic(mnemonic), Some((operand @ Operand::Term(_), _))) if matches!(mnemonic.format, Format::ThreeAndFour) && !expression.stat.is_set(Flag::E) => {
+                    let pc = address + expression.len() as u64;
+                    if let Ok(evaluation) = Self::evaluate(operand, symbol_table, location) {
+                        let far = match evaluation.symbol() {
+                            Ok(symbol) if symbol.kind == SymbolKind::Relative => {
+                                Self::get_addr(symbol.value, pc, &base).is_none()
+                            }
+                            Ok(symbol) if symbol.kind == SymbolKind::Absolute => {
+                                Self::needs_extension(evaluation.value)
+                            }
+                            _ => {
+                                false
+                            }
+                        };
+                        if far || !evaluation.externals.is_empty() {
+                            res.push(idx);
+                        }
+                    }
+                }
+                (Command::Mnemonic(mnemonic), Some((Operand::Literal(Literal::Integer(num)), _))) if matches!(mnemonic.format, Format::ThreeAndFour) && !expression.stat.is_set(Flag::E) && Self::needs_extension(*num as i64) => {
+                    res.push(idx);
+                }
+                _ => {}
+            }
+        }
+
+        res
+    }
 
-        let lines = BufReader::new(file).lines();
+    fn parse(expressions: &[Expression], symbol_table: &SymbolTable, locations: &[Location], mode: Mode, diagnostics: &mut Diagnostics) -> (Vec<String>, Vec<ModificationSection>) {
+        let mut res = vec![];
+        let mut modifications = vec![];
+        let mut base = None;
 
-        for line in lines {
-            if let Ok(line) = line {
-                let expression = Expression::from_str(&line)
-                    .expect(format!("Incorrect instruction on line {}", line_cnt).as_str());
-                expressions.push(expression);
-                line_cnt += 1;
+        for (expression, &location) in expressions.iter().zip(locations) {
+            if let Err(diagnostic) = Self::parse_statement(expression, location, symbol_table, mode, &mut base, &mut res, &mut modifications) {
+                diagnostics.push(diagnostic);
+                // keep the codes in step with the statements
+                res.push(String::new());
             }
         }
 
-        let mut header = HeaderSection::from_expression(&expressions[0]).unwrap();
-        let symbol_table = Self::get_symbols(&expressions, header.start_address).unwrap();
-        let op_codes = Self::parse(&expressions, &symbol_table, header.start_address).unwrap();
-        let end = EndSection::from_expression(&expressions[expressions.len() - 1], &symbol_table).unwrap();
+        (res, modifications)
+    }
+
+    // problems with single statements end up in diagnostics, only a broken header stops the section
+    fn assemble_section(expressions: &[Expression], options: Options, diagnostics: &mut Diagnostics) -> Result<(ControlSection, Vec<ListingLine>), Diagnostic> {
+        let mut header = HeaderSection::from_expression(&expressions[0]).map_err(|err| expressions[0].operand_error(err))?;
+        // the name fields of the H, D, R and M records hold six characters
+        if header.program_name.chars().count() > 6 {
+            diagnostics.push(expressions[0].label_error(format!("Section name {} is longer than 6 characters", header.program_name)));
+        }
+        let mut expressions = expressions.to_vec();
+
+        // relaxation: promote what cannot be reached to format 4 and lay the section out again, until nothing moves
+        let Layout{symbol_table, locations, blocks} = loop {
+            let mut pass = vec![];
+            let layout = Self::get_symbols(&expressions, header.start_address, &mut pass);
+            let far = if options.relax && options.mode == Mode::XE {
+                Self::far_statements(&expressions, &layout.symbol_table, &layout.locations)
+            } else {
+                vec![]
+            };
+
+            if far.is_empty() {
+                diagnostics.extend(pass);
+                break layout;
+            }
+
+            for idx in far {
+                expressions[idx].extend();
+            }
+        };
+        let expressions = &expressions;
+        let (op_codes, modifications) = Self::parse(expressions, &symbol_table, &locations, options.mode, diagnostics);
         let mut texts =  vec![];
         let mut cur_text: TextSection = TextSection{expressions: vec![], start_address: header.start_address, len: 0};
-        let mut cur_addr = header.start_address;
-        let mut has_no_resv = false;
+        let mut defines = vec![];
+        let mut references = vec![];
+
+        for idx in 0..expressions.len() {
+            // RESB/RESW and the bookkeeping directives emit no code, so they only show up as gaps between records
+            if op_codes[idx].is_empty() {
+                continue;
+            }
+
+            let (addr, len) = (locations[idx].1, expressions[idx].len() as u64);
 
-        for idx in 1..(expressions.len() - 1) {
-            if cur_addr - cur_text.start_address + (expressions[idx].len() as u64) > 0x1D && has_no_resv {
-                cur_text.len = cur_addr - cur_text.start_address;
+            if !cur_text.expressions.is_empty() && (addr != cur_text.start_address + cur_text.len || cur_text.len + len > 0x1D) {
                 texts.push(cur_text);
-                cur_text = TextSection{expressions: vec![], start_address: cur_addr, len: 0};
-                has_no_resv = false;
+                cur_text = TextSection{expressions: vec![], start_address: addr, len: 0};
             }
 
-            match expressions[idx].command.0 {
-                Command::Directive(Directive::RESW) | Command::Directive(Directive::RESB) => {
-                    if has_no_resv {
-                        cur_text.len = cur_addr - cur_text.start_address;
-                        texts.push(cur_text);
-                        cur_text = TextSection{expressions: vec![], start_address: cur_addr, len: 0};
-                        has_no_resv = false;
+            if cur_text.expressions.is_empty() {
+                cur_text.start_address = addr;
+            }
+
+            cur_text.expressions.push((expressions[idx].clone(), op_codes[idx].clone()));
+            cur_text.len += len;
+        }
+
+        if !cur_text.expressions.is_empty() {
+            texts.push(cur_text);
+        }
+
+        for expression in expressions {
+            if let Command::Directive(Directive::EXTDEF | Directive::EXTREF) = expression.command.0 {
+                for name in expression.symbols().iter().filter(|name| name.chars().count() > 6) {
+                    diagnostics.push(expression.operand_error(format!("External symbol {} is longer than 6 characters", name)));
+                }
+            }
+
+            match expression.command.0 {
+                Command::Directive(Directive::EXTDEF) => {
+                    let mut symbols = vec![];
+                    for name in expression.symbols() {
+                        match symbol_table.get(&name) {
+                            Some(symbol) if symbol.kind != SymbolKind::External => {
+                                symbols.push((name, symbol.value));
+                            }
+                            _ => {
+                                diagnostics.push(expression.operand_error(format!("Symbol {} not found", name)));
+                            }
+                        }
+                    }
+                    // up to six names per D record
+                    for chunk in symbols.chunks(6) {
+                        defines.push(DefineSection{symbols: chunk.to_vec()});
                     }
                 }
-                _ => {
-                    if !has_no_resv {
-                        cur_text.start_address = cur_addr;
+                Command::Directive(Directive::EXTREF) => {
+                    // up to twelve names per R record
+                    for chunk in expression.symbols().chunks(12) {
+                        references.push(ReferSection{symbols: chunk.to_vec()});
                     }
-                    has_no_resv = true;
                 }
+                _ => {}
+            }
+        }
+
+        let listing = expressions.iter().zip(&locations).zip(op_codes).map(|((expression, &(block, address)), opcode)| {
+            ListingLine{source: expression.source, address, block, expression: Some(expression.clone()), opcode}
+        }).collect();
 
+        let last = &blocks[blocks.len() - 1];
+        header.len = last.start_address + last.len - header.start_address;
+        let end = EndSection{start_address: None};
+
+        Ok((ControlSection{symbol_table, blocks, header, defines, references, texts, modifications, end}, listing))
+    }
+
+    pub fn from_source(text: &str, mut options: Options) -> Result<Self, Diagnostics> {
+        let lines: Vec<String> = text.lines().map(String::from).collect();
+        let mut diagnostics = vec![];
+        let mut source = MacroProcessor::new().process(&lines, &mut diagnostics);
+
+        // a SIC line in front of the program selects SIC mode like the command line flag
+        let first = source.iter().position(|line| line.kind == LineKind::Source || line.kind == LineKind::Expansion);
+        if let Some(first) = first {
+            if lexer::parse_line_to_lexemes(&source[first].text) == ["SIC"] {
+                options.mode = Mode::SIC;
+                source[first].kind = LineKind::Control;
             }
+        }
+
+        let assembled = Self::assemble(&source, options, &mut diagnostics);
 
-            cur_text.expressions.push((expressions[idx].clone(), op_codes[idx - 1].clone()));
-            cur_addr += expressions[idx].len() as u64;
+        match assembled {
+            Some((sections, listing)) if diagnostics.is_empty() => {
+                Ok(Self{sections, listing, source})
+            }
+            _ => {
+                let mut diagnostics: Diagnostics = diagnostics.into_iter().map(|diagnostic| diagnostic.locate(&source)).collect();
+                diagnostics.sort_by_key(|diagnostic| diagnostic.line.as_ref().map(|(number, _)| *number));
+                Err(diagnostics)
+            }
         }
+    }
 
-        if cur_text.expressions.len() != 0 {
-            cur_text.len = cur_addr - cur_text.start_address;
-            texts.push(cur_text);
+    // the H/D/R/T/M/E records of every control section, one per line
+    pub fn object_program(self: &Self) -> String {
+        let mut res = String::new();
+
+        for section in &self.sections {
+            res += &format!("{}\n", section.header);
+
+            for define in &section.defines {
+                res += &format!("{}\n", define);
+            }
+
+            for refer in &section.references {
+                res += &format!("{}\n", refer);
+            }
+
+            for text in &section.texts {
+                res += &format!("{}\n", text);
+            }
+
+            for modification in &section.modifications {
+                res += &format!("{}\n", modification);
+            }
+
+            res += &format!("{}\n", section.end);
+        }
+
+        res
+    }
+
+    // every source line with its address, block and object code, followed by the program blocks
+    pub fn listing(self: &Self) -> String {
+        let mut res = format!("{:>04}  {:>08} {:>5} {:>12} {:>12} {:>12} opcode\n", "line", "address", "block", "label", "operate", "operand");
+
+        for listing in &self.listing {
+            let source = &self.source[listing.source];
+            // statements generated by a macro call are marked with +
+            let marker = if source.kind == LineKind::Expansion { '+' } else { ' ' };
+
+            match &listing.expression {
+                Some(expression) => {
+                    match &expression.comment {
+                        Some(comment) => {
+                            res += &format!("{:>04}{} {:>8X} {:>5} {} {:<8} {}\n", source.number, marker, listing.address, listing.block, expression, listing.opcode, comment);
+                        }
+                        None => {
+                            res += &format!("{:>04}{} {:>8X} {:>5} {} {:>}\n", source.number, marker, listing.address, listing.block, expression, listing.opcode);
+                        }
+                    }
+                }
+                None => {
+                    let line = format!("{:>04}{} {:>8} {:>5} {}", source.number, marker, "", "", source.text);
+                    res += &format!("{}\n", line.trim_end());
+                }
+            }
+        }
+
+        res += "\n";
+        res += &format!("{:>12} {:>12} {:>6} {:>8} {:>8}\n", "section", "block", "number", "address", "length");
+
+        for section in &self.sections {
+            for (idx, block) in section.blocks.iter().enumerate() {
+                let name = if block.name.is_empty() { "(default)" } else { &block.name };
+                res += &format!("{:>12} {:>12} {:>6} {:>8X} {:>8X}\n", section.header.program_name, name, idx, block.start_address, block.len);
+            }
         }
 
-        header.len = cur_addr - header.start_address;
-        Ok(Self{symbol_table, header, texts, end})
+        res
+    }
+
+    // the records as the loader sees them, ready for LoadedProgram::link
+    pub fn object_programs(self: &Self) -> Vec<ObjectProgram> {
+        self.sections.iter().map(|section| {
+            let texts = section.texts.iter().map(|text| {
+                let bytes = text.expressions.iter().flat_map(|(_, code)| hex::decode(code).unwrap_or_default()).collect();
+                TextRecord{address: text.start_address, bytes}
+            }).collect();
+
+            ObjectProgram{
+                header: section.header.clone(),
+                defines: section.defines.iter().flat_map(|define| define.symbols.clone()).collect(),
+                references: section.references.iter().flat_map(|refer| refer.symbols.clone()).collect(),
+                texts,
+                modifications: section.modifications.clone(),
+                start_address: section.end.start_address
+            }
+        }).collect()
     }
-}
\ No newline at end of file
+
+    // goes on after a bad statement so that a single run reports as many problems as possible
+    fn assemble(source: &[SourceLine], options: Options, diagnostics: &mut Diagnostics) -> Option<(Vec<ControlSection>, Vec<ListingLine>)> {
+        let mut expressions = vec![];
+
+        for (idx, line) in source.iter().enumerate() {
+            match line.kind {
+                LineKind::Source | LineKind::Expansion => {
+                    let expression = match options.format {
+                        SourceFormat::Free => Expression::from_str(&line.text, options.mode),
+                        SourceFormat::Fixed => Expression::from_fixed(&line.text, options.mode)
+                    };
+
+                    match expression {
+                        Ok(mut expression) => {
+                            expression.source = idx;
+                            expressions.push(expression);
+                        }
+                        Err(diagnostic) => {
+                            diagnostics.push(diagnostic.at(idx));
+                            // keep the label of the broken statement, so that its uses are not reported too
+                            if !line.text.starts_with([' ', '\t']) {
+                                let label = match options.format {
+                                    SourceFormat::Free => lexer::parse_line_to_lexemes(&line.text).into_iter().next(),
+                                    SourceFormat::Fixed => lexer::parse_fixed_line(&line.text).0.into_iter().find(|(column, _)| *column < 8).map(|(_, label)| label)
+                                };
+                                if let Some(label) = label {
+                                    if let Ok(mut expression) = Expression::from_str(&format!("{} EQU *", label), options.mode) {
+                                        expression.source = idx;
+                                        expressions.push(expression);
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+                LineKind::Definition | LineKind::Call | LineKind::Control | LineKind::Comment => {}
+            }
+        }
+
+        let expressions = match Self::place_literals(expressions) {
+            Ok(expressions) => expressions,
+            Err(diagnostic) => {
+                diagnostics.push(diagnostic);
+                return None;
+            }
+        };
+
+        // every START/CSECT opens a control section that runs up to the next one
+        let mut bounds = vec![];
+
+        for (idx, expression) in expressions.iter().enumerate() {
+            match expression.command.0 {
+                Command::Directive(Directive::START) if idx != 0 => {
+                    diagnostics.push(expression.command_error("START must be the first statement"));
+                }
+                Command::Directive(Directive::END) if idx != expressions.len() - 1 => {
+                    diagnostics.push(expression.command_error("END must be the last statement"));
+                }
+                Command::Directive(Directive::START) | Command::Directive(Directive::CSECT) => {
+                    bounds.push(idx);
+                }
+                _ => {}
+            }
+        }
+
+        if bounds.first() != Some(&0) {
+            match expressions.first() {
+                Some(expression) => {
+                    diagnostics.push(expression.command_error("Not start directive").with_note("a program has to begin with START"));
+                }
+                None => {
+                    diagnostics.push(Diagnostic::error("Not start directive"));
+                }
+            }
+            return None;
+        }
+
+        bounds.push(expressions.len());
+
+        let mut sections = vec![];
+        let mut listing = vec![];
+
+        for bound in bounds.windows(2) {
+            match Self::assemble_section(&expressions[bound[0]..bound[1]], options, diagnostics) {
+                Ok((section, lines)) => {
+                    sections.push(section);
+                    listing.extend(lines);
+                }
+                Err(diagnostic) => {
+                    diagnostics.push(diagnostic);
+                }
+            }
+        }
+
+        if sections.is_empty() {
+            return None;
+        }
+
+        // comments, macro definitions, calls and conditional directives are only listed, between the statements around them
+        for (idx, line) in source.iter().enumerate() {
+            if line.kind != LineKind::Source && line.kind != LineKind::Expansion {
+                listing.push(ListingLine{source: idx, address: 0, block: 0, expression: None, opcode: String::new()});
+            }
+        }
+        listing.sort_by_key(|line| line.source);
+
+        // only the first control section tells the loader where to start
+        let end = &expressions[expressions.len() - 1];
+        match EndSection::from_expression(end, &sections[0].symbol_table) {
+            Ok(section) => {
+                sections[0].end = section;
+            }
+            Err(err) => {
+                diagnostics.push(end.operand_error(err));
+            }
+        }
+
+        Some((sections, listing))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(source: &str, options: Options) -> Vec<String> {
+        match ParserData::from_source(source, options) {
+            Ok(_) => vec![],
+            Err(diagnostics) => diagnostics.into_iter().map(|diagnostic| diagnostic.message).collect()
+        }
+    }
+
+    fn records(source: &str, options: Options) -> String {
+        match ParserData::from_source(source, options) {
+            Ok(data) => data.object_program(),
+            Err(diagnostics) => panic!("{:?}", diagnostics.iter().map(|diagnostic| diagnostic.to_string()).collect::<Vec<_>>())
+        }
+    }
+
+    const WIDE: &str = "PROG    START   0
+        LDA     #-1
+        LDA     #5000
+       +LDA     2000000
+        LDT     #BUFEND-BUFFER
+        LDA     #4095
+       +LDA     #4096
+BUFFER  RESB    4096
+BUFEND  EQU     *
+        END     PROG
+";
+
+    #[test]
+    fn operands_must_fit_the_address_field() {
+        assert_eq!(messages(WIDE, Options::default()), [
+            "Value -1 does not fit in the 12-bit address field",
+            "Value 5000 does not fit in the 12-bit address field",
+            "Value 2000000 does not fit in the 20-bit address field",
+            "Value 4096 does not fit in the 12-bit address field"
+        ]);
+    }
+
+    #[test]
+    fn comments_are_kept_in_the_listing() {
+        let source = ". copies nothing
+PROG    START   0
+
+FIRST   LDA     #5      load five
+        RSUB            back . to caller
+        END     FIRST
+";
+        assert_eq!(records(source, Options::default()), "HPROG  000000000006\nT000000060100054F0000\nE000000\n");
+
+        let listing = ParserData::from_source(source, Options::default()).ok().unwrap().listing();
+        assert!(listing.contains("0001                 . copies nothing\n0002 "));
+        assert!(listing.contains("010005   load five\n"));
+        assert!(listing.contains("4F0000   back . to caller\n"));
+    }
+
+    #[test]
+    fn fixed_columns_tell_the_fields_apart() {
+        // FIX is a mnemonic, but in columns 1-8 it can only be a label
+        let source = "PROG     START  0
+FIX      LDA   #5                 load five
+        +JSUB   FIX
+         END    PROG
+";
+        let fixed = Options{format: SourceFormat::Fixed, ..Options::default()};
+        assert_eq!(records(source, fixed), "HPROG  000000000007\nT000000070100054B100000\nM00000405\nE000000\n");
+        assert_eq!(messages(source, Options::default()), ["Symbol FIX not found"]);
+    }
+
+    #[test]
+    fn record_names_have_six_characters() {
+        let source = "LONGPROG START   0
+        EXTDEF  LONGNAME,SHORT
+        EXTREF  OTHERSYMBOL
+       +JSUB    OTHERSYMBOL
+LONGNAME RESW   1
+SHORT   RESW    1
+        END     LONGPROG
+";
+        assert_eq!(messages(source, Options::default()), [
+            "Section name LONGPROG is longer than 6 characters",
+            "External symbol LONGNAME is longer than 6 characters",
+            "External symbol OTHERSYMBOL is longer than 6 characters"
+        ]);
+    }
+
+    #[test]
+    fn relax_promotes_wide_values() {
+        // only the values that fit in 20 bits can be helped
+        assert_eq!(messages(WIDE, Options{relax: true, ..Options::default()}), [
+            "Value -1 does not fit in the 12-bit address field",
+            "Value 2000000 does not fit in the 20-bit address field"
+        ]);
+
+        let source = WIDE.lines().filter(|line| !line.contains("#-1") && !line.contains("2000000")).collect::<Vec<_>>().join("\n");
+        assert_eq!(records(&source, Options{relax: true, ..Options::default()}), "HPROG  00000000100F\nT0000000F0110138875101000010FFF01101000\nE000000\n");
+    }
+
+    #[test]
+    fn relax_promotes_far_addresses() {
+        let source = "PROG    START   0
+        LDA     FAR
+GAP     RESB    5000
+FAR     WORD    1
+        END     PROG
+";
+        assert_eq!(messages(source, Options::default()), ["Address 138B is out of range for PC relative and base relative addressing"]);
+        assert_eq!(records(source, Options{relax: true, ..Options::default()}), "HPROG  00000000138F\nT000000040310138C\nT00138C03000001\nM00000105\nE000000\n");
+    }
+
+    #[test]
+    fn arithmetic_overflow_is_reported() {
+        let source = "PROG    START   0
+VA      EQU     99999*99999
+VB      EQU     9999999999*9999999999
+VC      EQU     9223372036854775807+1
+VE      EQU     5/0
+        END     PROG
+";
+        assert_eq!(messages(source, Options::default()), [
+            "Value 9999800001 does not fit in a 24-bit word",
+            "Arithmetic overflow in expression",
+            "Arithmetic overflow in expression",
+            "Division by zero in expression"
+        ]);
+    }
+
+    #[test]
+    fn words_are_24_bits() {
+        let source = "PROG    START   0
+W1      WORD    -1
+W2      WORD    16777215
+        END     PROG
+";
+        assert_eq!(records(source, Options::default()), "HPROG  000000000006\nT00000006FFFFFFFFFFFF\nE000000\n");
+        assert_eq!(messages(&source.replace("16777215", "16777216"), Options::default()), ["Value 16777216 does not fit in a 24-bit word"]);
+    }
+
+    #[test]
+    fn reserved_space_must_fit_in_memory() {
+        let source = "PROG    START   0
+A1      RESB    -5
+A2      RESW    9999999
+        END     PROG
+";
+        assert_eq!(messages(source, Options::default()), [
+            "Cannot reserve a negative number of bytes",
+            "9999999 words do not fit in the 1048576 byte address space"
+        ]);
+
+        let source = "PROG    START   0
+B1      RESB    1000000
+B2      RESB    1000000
+        END     PROG
+";
+        assert_eq!(messages(source, Options::default()), ["The program does not fit in the 1048576 byte address space"]);
+    }
+
+    #[test]
+    fn org_errors_name_the_failed_check() {
+        let source = "PROG START 1000
+ ORG LATER
+ ORG 100
+ USE DATA
+ ORG 1200
+LATER RESB 1
+ END PROG
+";
+        assert_eq!(messages(source, Options::default()), [
+            "Cannot evaluate the ORG operand: Symbol LATER not found",
+            "ORG address 64 lies before the start of the program at 1000",
+            "ORG to the absolute address 4B0 is only allowed in the default block"
+        ]);
+    }
+
+    #[test]
+    fn xe_commands_in_sic_mode() {
+        let source = "PROG    START   0
+        LDB     TAB
+        BASE    TAB
+        CLEAR   X
+TAB     WORD    5
+        END     PROG
+";
+        assert_eq!(messages(source, Options{mode: Mode::SIC, ..Options::default()}), [
+            "LDB is a SIC/XE instruction, not available in SIC mode",
+            "BASE is a SIC/XE directive, not available in SIC mode",
+            "CLEAR is a SIC/XE instruction, not available in SIC mode"
+        ]);
+        assert_eq!(messages(&format!("SIC\n{}", source), Options::default()).len(), 3);
+    }
+
+    #[test]
+    fn macro_errors_do_not_stop_assembly() {
+        let source = "PROG    START   0
+&V      SET     1/0
+        ELSE
+        LDA     UNDEF
+        END     PROG
+";
+        let diagnostics = ParserData::from_source(source, Options::default()).err().unwrap();
+        let found: Vec<(usize, &str)> = diagnostics.iter().map(|diagnostic| (diagnostic.line.as_ref().unwrap().0, diagnostic.message.as_str())).collect();
+
+        assert_eq!(found, [(2, "Division by zero in condition"), (3, "ELSE without IF"), (4, "Symbol UNDEF not found")]);
+        assert!(diagnostics.iter().all(|diagnostic| diagnostic.span.is_some()));
+    }
+}
@@ -1,5 +1,5 @@
 use lazy_static::lazy_static;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 
 #[derive(PartialEq)]
@@ -14,7 +14,7 @@ pub enum Flag {
 
 impl Flag {
     pub fn from_prefix(lexeme: &str) -> Option<Flag> {
-        return match lexeme.chars().nth(0).unwrap() {
+        match lexeme.chars().nth(0).unwrap() {
             '+' => {
                 Some(Flag::E)
             }
@@ -49,12 +49,6 @@ impl Stat {
         self.0 |= 1 << (flag as i32);
     }
 
-    #[inline]
-    pub fn unset(self: &mut Self, flag: Flag) {
-        self.0 ^= 1 << (flag as i32);
-    }
-
-
     #[inline]
     pub fn is_set(self: &Self, flag: Flag) -> bool {
         (self.0 & 1 << (flag as i32)) != 0
@@ -62,9 +56,11 @@ impl Stat {
 
     pub fn is_valid(self: &Self) -> bool {
         // P and B and E cannot be all set up
+        let relative = self.is_set(Flag::P) && self.is_set(Flag::B) && self.is_set(Flag::E);
         // N or I cannot be set with X
-        !((self.is_set(Flag::P) ^ self.is_set(Flag::B) ^ self.is_set(Flag::E)) && (self.is_set(Flag::P) && self.is_set(Flag::B) && self.is_set(Flag::E)))
-            && !(self.is_set(Flag::N) ^ self.is_set(Flag::I) && self.is_set(Flag::X))
+        let indexed = self.is_set(Flag::N) ^ self.is_set(Flag::I) && self.is_set(Flag::X);
+
+        !(relative || indexed)
     }
 
 
@@ -117,6 +113,7 @@ impl Register {
     }
 }
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Copy, Clone)]
 pub enum Format {
     ONE,
@@ -128,7 +125,7 @@ impl Format {
     pub fn len(self: &Self) -> usize {
         match self {
             Self::ONE => {
-                3
+                1
             }
             Self::TWO => {
                 2
@@ -141,6 +138,8 @@ impl Format {
 }
 
 
+// spelled the way they are written in the source
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Copy, Clone, PartialEq)]
 pub enum Directive {
     START,
@@ -149,13 +148,21 @@ pub enum Directive {
     WORD,
     RESB,
     RESW,
-    BASE
+    BASE,
+    EQU,
+    ORG,
+    LTORG,
+    USE,
+    CSECT,
+    EXTDEF,
+    EXTREF
 }
 
 impl Directive {
     pub fn len(self: &Self) -> usize {
         match self {
-            Self::BASE | Self::START | Self::END => {
+            Self::BASE | Self::START | Self::END | Self::EQU | Self::ORG | Self::LTORG | Self::USE
+            | Self::CSECT | Self::EXTDEF | Self::EXTREF => {
                 0
             }
             Self::BYTE | Self::RESB => {
@@ -169,12 +176,55 @@ impl Directive {
 
 }
 
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy, Clone, Default, PartialEq)]
+pub enum Mode {
+    // the original SIC machine: no registers beyond A/X/L, 15-bit direct addresses
+    SIC,
+    #[default]
+    XE
+}
+
+impl Mode {
+    pub fn command(self: &Self, name: &str) -> Option<Command> {
+        match self {
+            Mode::SIC if !SIC_COMMANDS.contains(name) => {
+                None
+            }
+            _ => {
+                COMMANDS.get(name).copied()
+            }
+        }
+    }
+}
+
 #[derive(Copy, Clone)]
 pub enum Command {
     Directive(Directive),
     Mnemonic(Mnemonic)
 }
 
+impl Command {
+    // whatever follows the command of one of these is a comment
+    pub fn takes_operand(self: &Self) -> bool {
+        match self {
+            Command::Directive(Directive::LTORG) | Command::Directive(Directive::CSECT) => {
+                false
+            }
+            Command::Mnemonic(Mnemonic{format: Format::ONE, ..}) => {
+                false
+            }
+            Command::Mnemonic(Mnemonic{opcode: 0x4C, ..}) => {
+                // RSUB
+                false
+            }
+            _ => {
+                true
+            }
+        }
+    }
+}
+
 #[derive(Copy, Clone)]
 pub struct Mnemonic {
     pub opcode: u8,
@@ -222,7 +272,7 @@ lazy_static! {
         map.insert("OR",     Command::Mnemonic(Mnemonic{opcode: 0x44, format: Format::ThreeAndFour}));
         map.insert("RD",     Command::Mnemonic(Mnemonic{opcode: 0xD8, format: Format::ThreeAndFour}));
         map.insert("RMO",    Command::Mnemonic(Mnemonic{opcode: 0xAC, format: Format::TWO}));
-        map.insert("RSUB",   Command::Mnemonic(Mnemonic{opcode: 0x4C, format: Format::ONE}));
+        map.insert("RSUB",   Command::Mnemonic(Mnemonic{opcode: 0x4C, format: Format::ThreeAndFour}));
         map.insert("SHIFTL", Command::Mnemonic(Mnemonic{opcode: 0xA4, format: Format::TWO}));
         map.insert("SHIFTR", Command::Mnemonic(Mnemonic{opcode: 0xA8, format: Format::TWO}));
         map.insert("SIO",    Command::Mnemonic(Mnemonic{opcode: 0xF0, format: Format::ONE}));
@@ -253,6 +303,40 @@ lazy_static! {
         map.insert("RESB",   Command::Directive(Directive::RESB));
         map.insert("RESW",   Command::Directive(Directive::RESW));
         map.insert("BASE",   Command::Directive(Directive::BASE));
+        map.insert("EQU",    Command::Directive(Directive::EQU));
+        map.insert("ORG",    Command::Directive(Directive::ORG));
+        map.insert("LTORG",  Command::Directive(Directive::LTORG));
+        map.insert("USE",    Command::Directive(Directive::USE));
+        map.insert("CSECT",  Command::Directive(Directive::CSECT));
+        map.insert("EXTDEF", Command::Directive(Directive::EXTDEF));
+        map.insert("EXTREF", Command::Directive(Directive::EXTREF));
         map
     };
-}
\ No newline at end of file
+}
+
+lazy_static! {
+    pub static ref SIC_COMMANDS: HashSet<&'static str> = {
+        HashSet::from([
+            "ADD", "AND", "COMP", "DIV", "J", "JEQ", "JGT", "JLT", "JSUB", "LDA", "LDCH", "LDL", "LDX",
+            "MUL", "OR", "RD", "RSUB", "STA", "STCH", "STL", "STSW", "STX", "SUB", "TD", "TIX", "WD",
+            "START", "END", "BYTE", "WORD", "RESB", "RESW", "EQU", "ORG", "LTORG", "USE", "CSECT",
+            "EXTDEF", "EXTREF"
+        ])
+    };
+}
+
+lazy_static! {
+    // COMMANDS the other way round, for reading machine code back
+    pub static ref OPCODES: HashMap<u8, (&'static str, Format)> = {
+        COMMANDS.iter().filter_map(|(name, command)| {
+            match command {
+                Command::Mnemonic(mnemonic) => {
+                    Some((mnemonic.opcode, (*name, mnemonic.format)))
+                }
+                Command::Directive(_) => {
+                    None
+                }
+            }
+        }).collect()
+    };
+}
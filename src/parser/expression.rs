@@ -1,13 +1,26 @@
 use std::error::Error;
 use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use crate::diagnostic::Diagnostic;
 use crate::lexer;
 use crate::parser::command::*;
 use crate::parser::token::{Token, Literal};
+use crate::parser::term::Term;
+use crate::simulator::MEMORY_SIZE;
 
 #[derive(Clone)]
 pub enum Operand {
     Literal(Literal),
-    Symbol(String),
+    Term(Term),
+    SymbolList(Vec<String>),
+}
+
+// column and length of the label, command and operand in the source line
+#[derive(Clone, Copy, Default)]
+pub struct Columns {
+    pub label: Option<(usize, usize)>,
+    pub command: Option<(usize, usize)>,
+    pub operand: Option<(usize, usize)>
 }
 
 #[derive(Clone)]
@@ -15,7 +28,11 @@ pub struct Expression {
     pub command: (Command, String),
     pub operand: Option<(Operand, String)>,
     pub label: Option<String>,
-    pub stat: Stat
+    pub stat: Stat,
+    // index of the source line the statement was read from
+    pub source: usize,
+    pub columns: Columns,
+    pub comment: Option<String>
 }
 
 impl Display for Expression {
@@ -42,27 +59,107 @@ impl Display for Expression {
 
 impl Expression {
 
-    fn is_valid(self: Self) -> Result<Self, Box<dyn Error>> {
+    fn is_valid(self: Self) -> Result<Self, Diagnostic> {
         // TODO: check size of operand
         // TODO: check type 2 operand
         match self.command.0 {
             Command::Directive(d) => {
                 match d {
                     Directive::START | Directive::BYTE | Directive::WORD | Directive::RESB | Directive::RESW => {
-                        if self.label.is_some() && self.operand.is_some() {
+                        if let (Some(_), Some((operand, _))) = (&self.label, &self.operand) {
                             if d == Directive::BYTE {
-                                if let Operand::Literal(Literal::RegisterPair(_)) = &(self.operand.as_ref().unwrap().0) {
-                                    Err("Operand must not be Register file for BYTE directive")?
+                                if let Operand::Literal(Literal::RegisterPair(_)) = operand {
+                                    Err(self.operand_error("Operand must not be Register file for BYTE directive"))?
                                 } else{
                                     Ok(self)
                                 }
-                            } else if let Operand::Literal(Literal::Integer(_)) = &(self.operand.as_ref().unwrap().0) {
+                            } else if let Operand::Literal(Literal::Integer(count)) = operand {
+                                if let Directive::RESB | Directive::RESW = d {
+                                    let unit = if d == Directive::RESB { "bytes" } else { "words" };
+                                    if *count < 0 {
+                                        Err(self.operand_error(format!("Cannot reserve a negative number of {}", unit)))?
+                                    }
+                                    // counted in bytes, so that a RESW cannot wrap the location counter either
+                                    if d.len() as u64 * *count as u64 > MEMORY_SIZE as u64 {
+                                        Err(self.operand_error(format!("{} {} do not fit in the {} byte address space", count, unit, MEMORY_SIZE)))?
+                                    }
+                                }
+                                Ok(self)
+                            } else if let (Directive::WORD, Operand::Term(_)) = (d, operand) {
                                 Ok(self)
                             } else {
-                                Err("Operand must be Integer for directive")?
+                                Err(self.operand_error("Operand must be Integer for directive"))?
+                            }
+                        } else {
+                            Err(self.command_error("Miss label or operand for directive"))?
+                        }
+                    }
+
+                    Directive::EQU => {
+                        if let (Some(_), Some((operand, _))) = (&self.label, &self.operand) {
+                            match operand {
+                                Operand::Literal(Literal::Integer(_)) | Operand::Term(_) => {
+                                    Ok(self)
+                                }
+                                _ => {
+                                    Err(self.operand_error("Operand must be Integer or an expression for EQU directive"))?
+                                }
+                            }
+                        } else {
+                            Err(self.command_error("Miss label or operand for EQU directive"))?
+                        }
+                    }
+
+                    Directive::ORG => {
+                        if self.label.is_some() {
+                            Err(self.label_error("Must with no label for ORG directive"))?
+                        }
+
+                        match &self.operand {
+                            None | Some((Operand::Literal(Literal::Integer(_)), _)) | Some((Operand::Term(_), _)) => {
+                                Ok(self)
+                            }
+                            _ => {
+                                Err(self.operand_error("Operand must be Integer or an expression for ORG directive"))?
+                            }
+                        }
+                    }
+
+                    Directive::USE => {
+                        match (&self.label, &self.operand) {
+                            (None, None) | (None, Some((Operand::Term(Term::Symbol(_)), _))) => {
+                                Ok(self)
+                            }
+                            _ => {
+                                Err(self.command_error("Must with no label and a block name or nothing for USE directive"))?
+                            }
+                        }
+                    }
+
+                    Directive::CSECT => {
+                        if self.label.is_some() && self.operand.is_none() {
+                            Ok(self)
+                        } else {
+                            Err(self.command_error("Must with label and no operand for CSECT directive"))?
+                        }
+                    }
+
+                    Directive::EXTDEF | Directive::EXTREF => {
+                        match (&self.label, &self.operand) {
+                            (None, Some((Operand::Term(Term::Symbol(_)), _))) | (None, Some((Operand::SymbolList(_), _))) => {
+                                Ok(self)
                             }
+                            _ => {
+                                Err(self.command_error("Must with no label and a list of symbols for EXTDEF/EXTREF directive"))?
+                            }
+                        }
+                    }
+
+                    Directive::LTORG => {
+                        if self.label.is_none() && self.operand.is_none() {
+                            Ok(self)
                         } else {
-                            Err("Miss label or operand for directive")?
+                            Err(self.command_error("Must with no label and operand for LTORG directive"))?
                         }
                     }
 
@@ -70,7 +167,7 @@ impl Expression {
                         if self.label.is_none() {
                             Ok(self)
                         } else {
-                            Err("Must with no label for END directive")?
+                            Err(self.label_error("Must with no label for END directive"))?
                         }
                     }
                 }
@@ -82,37 +179,43 @@ impl Expression {
                         if self.stat.get_val() == 0 && self.operand.is_none() {
                             Ok(self)
                         } else {
-                            Err("Error format for format 1 instruction")?
+                            Err(self.command_error("Error format for format 1 instruction"))?
                         }
                     }
                     Format::TWO => {
-                        if self.stat.get_val() == 0 && self.operand.is_some() {
-                            if let Operand::Literal(Literal::RegisterPair(_)) = &(self.operand.as_ref().unwrap().0) {
+                        if let (0, Some((operand, _))) = (self.stat.get_val(), &self.operand) {
+                            if let Operand::Literal(Literal::RegisterPair(_)) = operand {
                                 Ok(self)
                             } else {
-                                Err("Must be Register Pair operand for format 2 instruction")?
+                                Err(self.operand_error("Must be Register Pair operand for format 2 instruction"))?
                             }
                         } else {
-                            Err("Error format for format 2 instruction")?
+                            Err(self.command_error("Error format for format 2 instruction"))?
                         }
                     }
                     Format::ThreeAndFour => {
-                        if self.operand.is_some() {
-                            match &(self.operand.as_ref().unwrap().0) {
+                        if let Some((operand, _)) = &self.operand {
+                            match operand {
                                 Operand::Literal(lit) => {
                                     if let Literal::RegisterPair(_) = lit {
-                                        Err("Must not be Register Pair operand for format 3/4 instruction")?
+                                        Err(self.operand_error("Must not be Register Pair operand for format 3/4 instruction"))?
                                     } else {
                                         Ok(self)
                                     }
                                 }
-                                Operand::Symbol(_) => {
+                                Operand::Term(_) => {
                                     Ok(self)
                                 }
+                                Operand::SymbolList(_) => {
+                                    Err(self.operand_error("Must not be a list of symbols for format 3/4 instruction"))?
+                                }
 
                             }
+                        } else if m.opcode == 0x4C {
+                            // RSUB is the only one returning through L instead of addressing memory
+                            Ok(self)
                         } else {
-                            Err("Miss operand for format 3/4 instruction")?
+                            Err(self.command_error("Miss operand for format 3/4 instruction"))?
                         }
                     }
                 }
@@ -120,6 +223,46 @@ impl Expression {
         }
     }
 
+    fn error_at(self: &Self, columns: Option<(usize, usize)>, message: impl Display) -> Diagnostic {
+        let diagnostic = Diagnostic::error(message.to_string()).at(self.source);
+        match columns.or(self.columns.command) {
+            Some((column, len)) => diagnostic.with_span(column, len),
+            None => diagnostic
+        }
+    }
+
+    pub fn label_error(self: &Self, message: impl Display) -> Diagnostic {
+        self.error_at(self.columns.label, message)
+    }
+
+    pub fn command_error(self: &Self, message: impl Display) -> Diagnostic {
+        self.error_at(self.columns.command, message)
+    }
+
+    pub fn operand_error(self: &Self, message: impl Display) -> Diagnostic {
+        self.error_at(self.columns.operand, message)
+    }
+
+    // turns a format 3 instruction into format 4, as if it had been written with +
+    pub fn extend(self: &mut Self) {
+        self.stat.set(Flag::E);
+        self.command.1 = format!("+{}", self.command.1);
+    }
+
+    pub fn symbols(self: &Self) -> Vec<String> {
+        match &self.operand {
+            Some((Operand::Term(Term::Symbol(symbol)), _)) => {
+                vec![symbol.clone()]
+            }
+            Some((Operand::SymbolList(list), _)) => {
+                list.clone()
+            }
+            _ => {
+                vec![]
+            }
+        }
+    }
+
     pub fn len(self: &Self) -> usize {
         match self.command.0 {
             Command::Mnemonic(mnemonic) => {
@@ -135,10 +278,17 @@ impl Expression {
                         }
                     }
                     Directive::BYTE => {
-                        if let Some((Operand::Literal(Literal::String(str)), _)) = &self.operand {
-                            directive.len() * str.len()
-                        } else {
-                            directive.len()
+                        match &self.operand {
+                            Some((Operand::Literal(Literal::String(str)), _)) => {
+                                directive.len() * str.len()
+                            }
+                            Some((Operand::Literal(Literal::Integer(_)), lexeme)) if lexeme.starts_with("X'") => {
+                                // two hex digits per byte
+                                directive.len() * (lexeme.len() - 2) / 2
+                            }
+                            _ => {
+                                directive.len()
+                            }
                         }
                     }
 
@@ -150,9 +300,52 @@ impl Expression {
         }
     }
 
-    pub fn from_str(line: &str) -> Result<Expression, Box<dyn Error>> {
-        let lexemes = lexer::parse_line_to_lexemes(line);
-        let tokens = Token::from_lexemes(&lexemes);
+    pub fn from_literal(name: &str) -> Result<Expression, Box<dyn Error>> {
+        let body = &name[1..];
+        let command = match Literal::from_str(body)? {
+            Literal::Integer(_) if !body.starts_with("X'") => {
+                (Command::Directive(Directive::WORD), String::from("*"))
+            }
+            _ => {
+                (Command::Directive(Directive::BYTE), String::from("*"))
+            }
+        };
+
+        Ok(Self{command, operand: Some((Operand::Literal(Literal::from_str(body)?), String::from(body))), label: Some(String::from(name)), stat: Stat::default(), source: 0, columns: Columns::default(), comment: None})
+    }
+
+    pub fn from_str(line: &str, mode: Mode) -> Result<Expression, Diagnostic> {
+        let mut spans = lexer::parse_line_to_spans(line);
+        let mut comment = lexer::comment_column(line);
+
+        // the command is the first or the second field and anything after its operand is a comment
+        let command = spans.iter().take(2).position(|(_, lexeme)| mode.command(lexeme.trim_start_matches('+')).is_some());
+        if let Some(idx) = command {
+            let command = mode.command(spans[idx].1.trim_start_matches('+')).unwrap();
+            let fields = idx + 1 + command.takes_operand() as usize;
+            if spans.len() > fields {
+                comment = Some(spans[fields].0);
+                spans.truncate(fields);
+            }
+        }
+
+        Self::from_spans(line, spans, command, comment, mode)
+    }
+
+    // card images, where the columns tell the label, the command and the operand apart
+    pub fn from_fixed(line: &str, mode: Mode) -> Result<Expression, Diagnostic> {
+        let (spans, command, comment) = lexer::parse_fixed_line(line);
+
+        Self::from_spans(line, spans, command, comment, mode)
+    }
+
+    fn from_spans(line: &str, spans: Vec<(usize, String)>, command: Option<usize>, comment: Option<usize>, mode: Mode) -> Result<Expression, Diagnostic> {
+        let comment = comment.map(|column| line.chars().skip(column).collect::<String>().trim_end().to_string());
+
+        let tokens = Token::from_lexemes(&spans, command, mode);
+        let lexemes: Vec<String> = spans.iter().map(|(_, lexeme)| lexeme.clone()).collect();
+        let columns: Vec<(usize, usize)> = spans.iter().map(|(column, lexeme)| (*column, lexeme.chars().count())).collect();
+        let at = |idx: usize, message: &str| Diagnostic::error(message).with_span(columns[idx].0, columns[idx].1);
 
         let mut command = None;
         let mut label = None;
@@ -171,7 +364,7 @@ impl Expression {
                         command = Some((*cmd, lexemes[0].clone()));
                     }
                     _ => {
-                        Err("Invalid expression(There must be a instruction in one line)")?
+                        Err(at(0, "Invalid expression(There must be a instruction in one line)"))?
                     }
                 }
             }
@@ -179,18 +372,13 @@ impl Expression {
             2 => {
                 match &tokens[0] {
                     Token::Symbol(sym) => {
-
-                        if COMMANDS.get(&sym[..]).is_some() {
-                            Err("label cannot be reserved word")?
-                        }
-
                         label = Some(String::from(sym));
                     }
                     Token::Command(cmd) => {
                         command = Some((*cmd, lexemes[0].clone()));
                     }
                     _ => {
-                        Err("Invalid expression(First Argument must be a Symbol)")?
+                        Err(at(0, "Invalid expression(First Argument must be a Symbol)"))?
                     }
                 }
 
@@ -201,13 +389,19 @@ impl Expression {
                                 command = Some((*cmd, lexemes[1].clone()));
                             }
                             Some(_) => {
-                                Err("Invalid expression(Too many command in one line)")?
+                                Err(at(1, "Invalid expression(Too many command in one line)"))?
                             }
 
                         }
                     }
                     Token::Symbol(sym) => {
-                        operand = Some((Operand::Symbol(String::from(sym)), lexemes[1].clone()));
+                        operand = Some((Operand::Term(Term::Symbol(String::from(sym))), lexemes[1].clone()));
+                    }
+                    Token::Term(term) => {
+                        operand = Some((Operand::Term(term.clone()), lexemes[1].clone()));
+                    }
+                    Token::SymbolList(list) => {
+                        operand = Some((Operand::SymbolList(list.clone()), lexemes[1].clone()));
                     }
                     Token::Literal(lit) => {
                         operand = Some((Operand::Literal(lit.clone()), lexemes[1].clone()));
@@ -218,15 +412,10 @@ impl Expression {
             3 => {
                 match &tokens[0] {
                     Token::Symbol(sym) => {
-
-                        if COMMANDS.get(&sym[..]).is_some() {
-                            Err("label cannot be reserved word")?
-                        }
-
                         label = Some(String::from(sym));
                     }
                     _ => {
-                        Err("Invalid expression(first token must be symbol)")?
+                        Err(at(0, "Invalid expression(first token must be symbol)"))?
                     }
                 }
                 match &tokens[1] {
@@ -234,7 +423,7 @@ impl Expression {
                         command = Some((*cmd, lexemes[1].clone()));
                     }
                     _ => {
-                        Err("Invalid expression(second token must be instruction)")?
+                        Err(at(1, "Invalid expression(second token must be instruction)"))?
                     }
                 }
                 match &tokens[2] {
@@ -242,27 +431,44 @@ impl Expression {
                         operand = Some((Operand::Literal(lit.clone()), lexemes[2].clone()));
                     }
                     Token::Symbol(sym) => {
-                        operand = Some((Operand::Symbol(String::from(sym)), lexemes[2].clone()));
+                        operand = Some((Operand::Term(Term::Symbol(String::from(sym))), lexemes[2].clone()));
+                    }
+                    Token::Term(term) => {
+                        operand = Some((Operand::Term(term.clone()), lexemes[2].clone()));
+                    }
+                    Token::SymbolList(list) => {
+                        operand = Some((Operand::SymbolList(list.clone()), lexemes[2].clone()));
                     }
                     _ => {
-                        Err("Invalid expression(third token must not be instruction)")?
+                        Err(at(2, "Invalid expression(third token must not be instruction)"))?
                     }
                 }
             }
 
+            0 => {
+                Err("Invalid expression: Instruction not found")?
+            }
+
             _ => {
-                Err("Invalid expression:(Too many token in one expression)")?
+                Err(at(3, "Invalid expression:(Too many token in one expression)"))?
             }
         }
 
-        if let None = command {
-            Err("Invalid expression: Instruction not found")?
+        if command.is_none() {
+            Err(at(0, "Invalid expression: Instruction not found"))?
         }
 
-        let mut res = Self{command: command.unwrap(), operand, label, stat };
+        // the label comes first and the operand last, whatever the number of tokens
+        let columns = Columns{
+            label: label.as_ref().map(|_| columns[0]),
+            command: Some(columns[label.is_some() as usize]),
+            operand: operand.as_ref().map(|_| columns[columns.len() - 1])
+        };
+        let mut res = Self{command: command.unwrap(), operand, label, stat, source: 0, columns, comment};
 
         if let (Command::Mnemonic(Mnemonic{opcode: _opcode, format: Format::ThreeAndFour}), _) = res.command {
-            if !stat.is_set(Flag::N) && !stat.is_set(Flag::I) {
+            // SIC has no n and i bits, the instruction keeps its plain 15-bit address
+            if mode == Mode::XE && !stat.is_set(Flag::N) && !stat.is_set(Flag::I) {
                 res.stat.set(Flag::N);
                 res.stat.set(Flag::I);
             }
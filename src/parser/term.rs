@@ -0,0 +1,130 @@
+use std::error::Error;
+use std::str::FromStr;
+use crate::parser::token::Token;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Operator {
+    Add,
+    Sub,
+    Mul,
+    Div
+}
+
+#[derive(Clone)]
+pub enum Term {
+    Integer(i64),
+    Symbol(String),
+    LocationCounter,
+    Negate(Box<Term>),
+    Binary(Box<Term>, Operator, Box<Term>)
+}
+
+impl FromStr for Term {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let chars: Vec<char> = s.chars().collect();
+        let mut pos = 0;
+        let term = Self::parse_sum(&chars, &mut pos)?;
+
+        if pos != chars.len() {
+            Err("Invalid expression")?
+        }
+
+        Ok(term)
+    }
+}
+
+impl Term {
+    // sum := product (('+' | '-') product)*
+    fn parse_sum(chars: &[char], pos: &mut usize) -> Result<Self, Box<dyn Error>> {
+        let mut term = Self::parse_product(chars, pos)?;
+
+        while *pos < chars.len() {
+            let operator = match chars[*pos] {
+                '+' => {
+                    Operator::Add
+                }
+                '-' => {
+                    Operator::Sub
+                }
+                _ => {
+                    break;
+                }
+            };
+            *pos += 1;
+            term = Term::Binary(Box::new(term), operator, Box::new(Self::parse_product(chars, pos)?));
+        }
+
+        Ok(term)
+    }
+
+    // product := factor (('*' | '/') factor)*
+    fn parse_product(chars: &[char], pos: &mut usize) -> Result<Self, Box<dyn Error>> {
+        let mut term = Self::parse_factor(chars, pos)?;
+
+        while *pos < chars.len() {
+            let operator = match chars[*pos] {
+                '*' => {
+                    Operator::Mul
+                }
+                '/' => {
+                    Operator::Div
+                }
+                _ => {
+                    break;
+                }
+            };
+            *pos += 1;
+            term = Term::Binary(Box::new(term), operator, Box::new(Self::parse_factor(chars, pos)?));
+        }
+
+        Ok(term)
+    }
+
+    // factor := '-' factor | '(' sum ')' | '*' | integer | symbol
+    fn parse_factor(chars: &[char], pos: &mut usize) -> Result<Self, Box<dyn Error>> {
+        match chars.get(*pos) {
+            Some('-') => {
+                *pos += 1;
+                Ok(Term::Negate(Box::new(Self::parse_factor(chars, pos)?)))
+            }
+            Some('(') => {
+                *pos += 1;
+                let term = Self::parse_sum(chars, pos)?;
+                if chars.get(*pos) != Some(&')') {
+                    Err("Unbalanced parenthesis in expression")?
+                }
+                *pos += 1;
+                Ok(term)
+            }
+            Some('*') => {
+                *pos += 1;
+                Ok(Term::LocationCounter)
+            }
+            Some(c) if c.is_ascii_digit() => {
+                let start = *pos;
+                while *pos < chars.len() && chars[*pos].is_ascii_digit() {
+                    *pos += 1;
+                }
+                let digits: String = chars[start..*pos].iter().collect();
+                Ok(Term::Integer(digits.parse()?))
+            }
+            Some(c) if c.is_alphanumeric() || *c == '_' || *c == '$' => {
+                let start = *pos;
+                *pos += 1;
+                while *pos < chars.len() && (chars[*pos].is_alphanumeric() || chars[*pos] == '_') {
+                    *pos += 1;
+                }
+                let name: String = chars[start..*pos].iter().collect();
+                if !Token::is_symbol(&name) {
+                    Err(format!("Invalid symbol {} in expression", name))?
+                }
+                Ok(Term::Symbol(name))
+            }
+            _ => {
+                Err("Invalid expression")?
+            }
+        }
+    }
+}
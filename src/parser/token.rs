@@ -1,6 +1,8 @@
 use std::error::Error;
 use std::str::FromStr;
+use crate::diagnostic::Diagnostic;
 use crate::parser::command::*;
+use crate::parser::term::Term;
 
 #[derive(Clone)]
 pub enum Literal{
@@ -16,12 +18,12 @@ impl Literal {
 
     fn get_register_pair(s: &str) -> Option<(Register, Register)> {
         if s.len() == 3 && s.chars().nth(1).unwrap() == ',' {
-            Some((Register::from_char(s.chars().nth(0).unwrap()).expect("Register not found"),
-                  Register::from_char(s.chars().nth(2).unwrap()).expect("Register not found"))
+            Some((Register::from_char(s.chars().nth(0).unwrap())?,
+                  Register::from_char(s.chars().nth(2).unwrap())?)
                 )
         } else if s.len() == 1 && s.chars().nth(0).unwrap().is_alphabetic() {
-            Some((Register::from_char(s.chars().nth(0).unwrap()).expect("Register not found"),
-                  Register::from_char('A').expect("Register not found"))
+            Some((Register::from_char(s.chars().nth(0).unwrap())?,
+                  Register::from_char('A')?)
                 )
         } else {
             None
@@ -36,7 +38,10 @@ impl FromStr for Literal {
         if Self::has_string(s) {
             match s.chars().nth(0).unwrap() {
                 'X' => {
-                    Ok(Self::Integer(i32::from_str_radix(&s[2..s.len() - 1], 16).expect("Not a number")))
+                    match i32::from_str_radix(&s[2..s.len() - 1], 16) {
+                        Ok(num) => Ok(Self::Integer(num)),
+                        Err(_) => Err(format!("{} is not a hexadecimal number", &s[2..s.len() - 1]))?
+                    }
                 }
                 'C' => {
                     Ok(Self::String(String::from(&s[2..s.len() - 1])))
@@ -63,6 +68,8 @@ pub enum Token {
     Literal(Literal),
     Symbol(String),
     Command(Command),
+    Term(Term),
+    SymbolList(Vec<String>),
 }
 
 
@@ -73,13 +80,8 @@ impl Token {
         }
         let prefix = lexeme.chars().nth(0).unwrap();
 
-        if !prefix.is_alphabetic() && prefix != '_' {
-            false
-        } else if lexeme.contains(|x: char| {!x.is_alphanumeric()}) {
-            false
-        } else {
-            true
-        }
+        // $ starts the unique labels generated by macro expansion
+        (prefix.is_alphabetic() || prefix == '_' || prefix == '$') && !lexeme[prefix.len_utf8()..].contains(|x: char| {!x.is_alphanumeric()})
     }
 
     fn is_valid(self: &Self, prefix: &Option<Flag>, suffix: &Option<Flag>) -> bool {
@@ -87,12 +89,12 @@ impl Token {
             return false;
         }
 
-        if let Token::Literal(Literal::RegisterPair(_)) = self {
+        if let Token::Literal(Literal::RegisterPair(_)) | Token::SymbolList(_) = self {
             return prefix.is_none() && suffix.is_none();
         }
 
         match self {
-            Token::Literal(_) | Token::Symbol(_) => {
+            Token::Literal(_) | Token::Symbol(_) | Token::Term(_) | Token::SymbolList(_) => {
                 if let Some(prefix) = prefix {
                     *prefix == Flag::N || *prefix == Flag::I
                 } else {
@@ -103,27 +105,43 @@ impl Token {
                 if let Some(prefix) = prefix {
                     *prefix == Flag::E
                 } else {
-                    *suffix == None
+                    suffix.is_none()
                 }
             }
         }
     }
 
-    pub fn from_lexemes(lexemes: &Vec<String>) -> Result<(Vec<Token>, Stat), Box<dyn Error>> {
+    // only the lexeme at index command is looked up as an instruction or directive
+    pub fn from_lexemes(lexemes: &[(usize, String)], command: Option<usize>, mode: Mode) -> Result<(Vec<Token>, Stat), Diagnostic> {
         let mut res = vec![];
         let mut stat = Stat::default();
 
-        for str in lexemes {
+        for (idx, (column, str)) in lexemes.iter().enumerate() {
+            let span = |message: String| Diagnostic::error(message).with_span(*column, str.chars().count());
             let prefix_flag = Flag::from_prefix(str);
             let suffix_flag = Flag::from_suffix(str);
             let mut lexeme: &str;
             let token: Token;
 
+            if mode == Mode::SIC && prefix_flag.is_some() {
+                Err(span(String::from("Immediate, indirect and extended addressing are not available in SIC mode"))
+                    .with_note("the standard SIC machine only has simple and indexed addressing"))?
+            }
+
+            // without a SIC command in front, an XE one is the likely mistake
+            if mode == Mode::SIC && command.map(|command| command == idx).unwrap_or(idx < 2) && mode.command(str).is_none() {
+                if let Some(xe) = Mode::XE.command(str) {
+                    let kind = if let Command::Directive(_) = xe { "directive" } else { "instruction" };
+                    Err(span(format!("{} is a SIC/XE {}, not available in SIC mode", str, kind))
+                        .with_note("SIC mode comes from --sic or a SIC line in front of the program"))?
+                }
+            }
+
             // eliminate prefix and suffix
             if prefix_flag.is_some() {
                 lexeme = &str[1..];
             } else {
-                lexeme = &str;
+                lexeme = str;
             }
 
             if suffix_flag.is_some() {
@@ -132,11 +150,25 @@ impl Token {
 
 
             // make token
-            if let Some(command) = COMMANDS.get(lexeme) {
-                token = Token::Command(*command);
+            if let Some(body) = lexeme.strip_prefix('=') {
+                // literals are named by their own text and resolved through the symbol table like labels
+                match Literal::from_str(body) {
+                    Ok(Literal::RegisterPair(_)) => {
+                        Err(span(String::from("Invalid literal")))?
+                    }
+                    Ok(_) => {}
+                    Err(err) => {
+                        Err(span(err.to_string()))?
+                    }
+                }
+                token = Token::Symbol(String::from(lexeme));
+            } else if let Some(command) = mode.command(lexeme).filter(|_| command == Some(idx)) {
+                token = Token::Command(command);
             } else {
                 if Self::is_symbol(lexeme) {
                     token = Token::Symbol(String::from(lexeme));
+                } else if lexeme.contains(',') && lexeme.split(',').all(Self::is_symbol) {
+                    token = Token::SymbolList(lexeme.split(',').map(String::from).collect());
                 } else {
                     let lit = Literal::from_str(lexeme);
 
@@ -145,7 +177,18 @@ impl Token {
                             token = Token::Literal(lit);
                         }
                         Err(err) => {
-                            return Err(err);
+                            // anything else has to be an arithmetic expression such as BUFEND-BUFFER or *
+                            match Term::from_str(lexeme) {
+                                Ok(term) => {
+                                    token = Token::Term(term);
+                                }
+                                Err(term_err) => {
+                                    if lexeme.contains(['+', '-', '*', '/', '(', ')']) {
+                                        return Err(span(term_err.to_string()));
+                                    }
+                                    return Err(span(err.to_string()));
+                                }
+                            }
                         }
 
                     }
@@ -153,7 +196,7 @@ impl Token {
             }
 
             if !token.is_valid(&prefix_flag, &suffix_flag) || !stat.is_valid() {
-                 Err("Invalid Token")?
+                 Err(span(String::from("Invalid Token")))?
             }
 
 
@@ -0,0 +1,497 @@
+use std::cmp::Ordering;
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use crate::AssembledProgram;
+use crate::loader::LoadedProgram;
+use crate::parser::command::{Format, OPCODES};
+
+pub const MEMORY_SIZE: usize = 1 << 20;
+
+// L starts out at the last byte of memory, so returning from the main routine stops the machine
+pub const HALT_ADDRESS: u32 = (MEMORY_SIZE - 1) as u32;
+
+#[derive(Clone, Copy, Default)]
+pub struct Registers {
+    pub a: u32,
+    pub x: u32,
+    pub l: u32,
+    pub b: u32,
+    pub s: u32,
+    pub t: u32,
+    pub f: f64,
+    pub pc: u32,
+    pub sw: u32
+}
+
+impl Display for Registers {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "A={:06X} X={:06X} L={:06X} B={:06X} S={:06X} T={:06X} F={} PC={:06X} SW={:06X}",
+               self.a, self.x, self.l, self.b, self.s, self.t, self.f, self.pc, self.sw)
+    }
+}
+
+#[derive(Default)]
+pub struct Device {
+    pub input: VecDeque<u8>,
+    pub output: Vec<u8>
+}
+
+pub struct Simulator {
+    pub memory: Vec<u8>,
+    pub registers: Registers,
+    pub devices: HashMap<u8, Device>,
+    pub halted: bool,
+    pub steps: usize
+}
+
+// 24-bit words are two's complement
+fn signed(word: u32) -> i32 {
+    ((word << 8) as i32) >> 8
+}
+
+fn word(value: i64) -> u32 {
+    (value as u32) & 0xFFFFFF
+}
+
+// 48-bit floats: sign, 11-bit exponent biased by 1024 and a 36-bit fraction 0.1xxx
+fn float_from_bits(bits: u64) -> f64 {
+    let fraction = bits & ((1u64 << 36) - 1);
+    let exponent = ((bits >> 36) & 0x7FF) as i32;
+
+    if fraction == 0 {
+        return 0.0;
+    }
+
+    let value = fraction as f64 / (1u64 << 36) as f64 * 2f64.powi(exponent - 1024);
+    if bits >> 47 == 1 { -value } else { value }
+}
+
+fn float_to_bits(value: f64) -> u64 {
+    if value == 0.0 || !value.is_finite() {
+        return 0;
+    }
+
+    let sign = (value < 0.0) as u64;
+    let mut exponent = value.abs().log2().floor() as i32 + 1;
+    let mut fraction = (value.abs() / 2f64.powi(exponent) * (1u64 << 36) as f64).round() as u64;
+
+    // log2 may be off by one at the edges, bring the fraction back into [0.5, 1)
+    while fraction >= 1u64 << 36 {
+        fraction >>= 1;
+        exponent += 1;
+    }
+    while fraction < 1u64 << 35 {
+        fraction <<= 1;
+        exponent -= 1;
+    }
+
+    (sign << 47) | ((((exponent + 1024) as u64) & 0x7FF) << 36) | fraction
+}
+
+impl Default for Simulator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Simulator {
+    pub fn new() -> Self {
+        let registers = Registers{l: HALT_ADDRESS, ..Default::default()};
+        Self{memory: vec![0; MEMORY_SIZE], registers, devices: HashMap::new(), halted: false, steps: 0}
+    }
+
+    pub fn load_bytes(self: &mut Self, address: u64, bytes: &[u8]) -> Result<(), Box<dyn Error>> {
+        let address = address as usize;
+
+        if address + bytes.len() > MEMORY_SIZE {
+            Err(format!("Code at {:06X} does not fit into memory", address))?
+        }
+
+        self.memory[address..address + bytes.len()].copy_from_slice(bytes);
+        Ok(())
+    }
+
+    // control sections are linked one after another from the start address of the first one
+    pub fn load(self: &mut Self, program: &AssembledProgram) -> Result<(), Box<dyn Error>> {
+        let programs = program.object_programs();
+        let address = programs.first().map(|program| program.header.start_address).unwrap_or(0);
+        let program = LoadedProgram::link(&programs, address)?;
+
+        // reserved space keeps whatever is in memory
+        for (start, len) in program.runs() {
+            self.load_bytes(program.address + start as u64, &program.bytes[start..start + len])?;
+        }
+
+        self.registers.pc = program.entry.unwrap_or(program.address) as u32;
+        Ok(())
+    }
+
+    pub fn device(self: &mut Self, id: u8) -> &mut Device {
+        self.devices.entry(id).or_default()
+    }
+
+    pub fn read(self: &Self, address: u32, len: usize) -> Result<u64, Box<dyn Error>> {
+        let address = address as usize;
+
+        if address + len > MEMORY_SIZE {
+            Err(format!("Memory access at {:06X} is out of range", address))?
+        }
+
+        Ok(self.memory[address..address + len].iter().fold(0, |value, &byte| (value << 8) | byte as u64))
+    }
+
+    pub fn write(self: &mut Self, address: u32, len: usize, value: u64) -> Result<(), Box<dyn Error>> {
+        let address = address as usize;
+
+        if address + len > MEMORY_SIZE {
+            Err(format!("Memory access at {:06X} is out of range", address))?
+        }
+
+        for idx in 0..len {
+            self.memory[address + idx] = (value >> (8 * (len - idx - 1))) as u8;
+        }
+        Ok(())
+    }
+
+    // condition code in the two top bits of the low byte of SW: 01 <, 00 =, 10 >
+    pub fn condition_code(self: &Self) -> Ordering {
+        match (self.registers.sw >> 6) & 0x3 {
+            1 => Ordering::Less,
+            2 => Ordering::Greater,
+            _ => Ordering::Equal
+        }
+    }
+
+    fn set_condition_code(self: &mut Self, ordering: Ordering) {
+        let bits = match ordering {
+            Ordering::Less => 1,
+            Ordering::Equal => 0,
+            Ordering::Greater => 2
+        };
+        self.registers.sw = (self.registers.sw & !0xC0) | (bits << 6);
+    }
+
+    fn register(self: &Self, number: u8) -> Result<u32, Box<dyn Error>> {
+        match number {
+            0 => Ok(self.registers.a),
+            1 => Ok(self.registers.x),
+            2 => Ok(self.registers.l),
+            3 => Ok(self.registers.b),
+            4 => Ok(self.registers.s),
+            5 => Ok(self.registers.t),
+            8 => Ok(self.registers.pc),
+            9 => Ok(self.registers.sw),
+            _ => Err(format!("Register {} cannot be used here", number))?
+        }
+    }
+
+    fn set_register(self: &mut Self, number: u8, value: u32) -> Result<(), Box<dyn Error>> {
+        let value = value & 0xFFFFFF;
+        match number {
+            0 => self.registers.a = value,
+            1 => self.registers.x = value,
+            2 => self.registers.l = value,
+            3 => self.registers.b = value,
+            4 => self.registers.s = value,
+            5 => self.registers.t = value,
+            8 => self.registers.pc = value,
+            9 => self.registers.sw = value,
+            _ => Err(format!("Register {} cannot be used here", number))?
+        }
+        Ok(())
+    }
+
+    fn execute_format_two(self: &mut Self, name: &str, r1: u8, r2: u8) -> Result<(), Box<dyn Error>> {
+        match name {
+            "CLEAR" => {
+                self.set_register(r1, 0)?;
+            }
+            "TIXR" => {
+                self.registers.x = word(self.registers.x as i64 + 1);
+                let other = signed(self.register(r1)?);
+                self.set_condition_code(signed(self.registers.x).cmp(&other));
+            }
+            "SVC" => {
+                Err("SVC is not supported by the simulator")?
+            }
+            "SHIFTL" => {
+                // circular, like the hardware
+                let value = self.register(r1)?;
+                let n = (r2 as u32 + 1) % 24;
+                self.set_register(r1, (value << n) | (value >> (24 - n)))?;
+            }
+            "SHIFTR" => {
+                // sign-filling
+                let value = signed(self.register(r1)?);
+                self.set_register(r1, word((value >> (r2 as u32 + 1).min(23)) as i64))?;
+            }
+            _ => {
+                let (v1, v2) = (signed(self.register(r1)?) as i64, signed(self.register(r2)?) as i64);
+                let value = match name {
+                    "ADDR" => v2 + v1,
+                    "SUBR" => v2 - v1,
+                    "MULR" => v2 * v1,
+                    "DIVR" if v1 == 0 => Err("Division by zero")?,
+                    "DIVR" => v2 / v1,
+                    "RMO" => v1,
+                    "COMPR" => {
+                        self.set_condition_code(v1.cmp(&v2));
+                        return Ok(());
+                    }
+                    _ => Err(format!("{} is not supported by the simulator", name))?
+                };
+                self.set_register(r2, word(value))?;
+            }
+        }
+        Ok(())
+    }
+
+    // returns false once the program has stopped
+    pub fn step(self: &mut Self) -> Result<bool, Box<dyn Error>> {
+        let pc = self.registers.pc;
+
+        if self.halted || pc == HALT_ADDRESS {
+            self.halted = true;
+            return Ok(false);
+        }
+
+        let b0 = self.read(pc, 1)? as u8;
+        let (name, format) = match OPCODES.get(&(b0 & 0xFC)) {
+            Some(&(name, format)) => (name, format),
+            None => Err(format!("Invalid opcode {:02X} at {:06X}", b0, pc))?
+        };
+
+        self.steps += 1;
+
+        match format {
+            Format::ONE => {
+                self.registers.pc = pc + 1;
+                match name {
+                    "FIX" => {
+                        self.registers.a = word(self.registers.f.trunc() as i64);
+                    }
+                    "FLOAT" => {
+                        self.registers.f = signed(self.registers.a) as f64;
+                    }
+                    "NORM" => {}
+                    _ => {
+                        Err(format!("{} is not supported by the simulator", name))?
+                    }
+                }
+            }
+            Format::TWO => {
+                let b1 = self.read(pc + 1, 1)? as u8;
+                self.registers.pc = pc + 2;
+                self.execute_format_two(name, b1 >> 4, b1 & 0x0F)?;
+            }
+            Format::ThreeAndFour => {
+                let (b1, b2) = (self.read(pc + 1, 1)? as u32, self.read(pc + 2, 1)? as u32);
+                let (n, i) = ((b0 >> 1) & 1 == 1, b0 & 1 == 1);
+                let (x, b, p, e) = (b1 & 0x80 != 0, b1 & 0x40 != 0, b1 & 0x20 != 0, b1 & 0x10 != 0);
+
+                let (len, mut target) = if !n && !i {
+                    // standard SIC: 15-bit address
+                    (3, ((b1 & 0x7F) << 8) | b2)
+                } else if e {
+                    if b || p {
+                        Err(format!("Format 4 at {:06X} cannot be relative", pc))?
+                    }
+                    (4, ((b1 & 0x0F) << 16) | (b2 << 8) | self.read(pc + 3, 1)? as u32)
+                } else {
+                    let disp = ((b1 & 0x0F) << 8) | b2;
+                    match (b, p) {
+                        (false, true) => {
+                            (3, word((pc + 3) as i64 + (((disp << 20) as i32) >> 20) as i64))
+                        }
+                        (true, false) => {
+                            (3, self.registers.b + disp)
+                        }
+                        (false, false) => {
+                            (3, disp)
+                        }
+                        _ => {
+                            Err(format!("Invalid addressing mode at {:06X}", pc))?
+                        }
+                    }
+                };
+
+                if x {
+                    target += self.registers.x;
+                }
+
+                let immediate = i && !n;
+                if n && !i {
+                    target = self.read(target & 0xFFFFF, 3)? as u32;
+                }
+                let target = target & 0xFFFFF;
+                let next = pc + len;
+                self.registers.pc = next;
+
+                self.execute_memory(name, target, immediate, next)?;
+
+                // J * is the usual way to stop a SIC program
+                if self.registers.pc == pc {
+                    self.halted = true;
+                }
+            }
+        }
+
+        if self.registers.pc == HALT_ADDRESS {
+            self.halted = true;
+        }
+
+        Ok(!self.halted)
+    }
+
+    fn operand(self: &Self, target: u32, immediate: bool, len: usize) -> Result<u64, Box<dyn Error>> {
+        if immediate {
+            Ok(target as u64)
+        } else {
+            self.read(target, len)
+        }
+    }
+
+    fn store(self: &mut Self, target: u32, immediate: bool, len: usize, value: u64) -> Result<(), Box<dyn Error>> {
+        if immediate {
+            Err("Cannot store into an immediate operand")?
+        }
+        self.write(target, len, value)
+    }
+
+    fn execute_memory(self: &mut Self, name: &str, target: u32, immediate: bool, next: u32) -> Result<(), Box<dyn Error>> {
+        match name {
+            "LDA" | "LDB" | "LDL" | "LDS" | "LDT" | "LDX" => {
+                let value = self.operand(target, immediate, 3)? as u32;
+                let registers = &mut self.registers;
+                match name {
+                    "LDA" => registers.a = value,
+                    "LDB" => registers.b = value,
+                    "LDL" => registers.l = value,
+                    "LDS" => registers.s = value,
+                    "LDT" => registers.t = value,
+                    _ => registers.x = value
+                }
+            }
+            "STA" | "STB" | "STL" | "STS" | "STT" | "STX" | "STSW" => {
+                let registers = &self.registers;
+                let value = match name {
+                    "STA" => registers.a,
+                    "STB" => registers.b,
+                    "STL" => registers.l,
+                    "STS" => registers.s,
+                    "STT" => registers.t,
+                    "STX" => registers.x,
+                    _ => registers.sw
+                };
+                self.store(target, immediate, 3, value as u64)?;
+            }
+            "LDCH" => {
+                let value = self.operand(target, immediate, 1)? as u32 & 0xFF;
+                self.registers.a = (self.registers.a & 0xFFFF00) | value;
+            }
+            "STCH" => {
+                let value = (self.registers.a & 0xFF) as u64;
+                self.store(target, immediate, 1, value)?;
+            }
+            "ADD" | "SUB" | "MUL" | "DIV" | "AND" | "OR" => {
+                let value = signed(self.operand(target, immediate, 3)? as u32) as i64;
+                let a = signed(self.registers.a) as i64;
+                self.registers.a = word(match name {
+                    "ADD" => a + value,
+                    "SUB" => a - value,
+                    "MUL" => a * value,
+                    "DIV" if value == 0 => Err("Division by zero")?,
+                    "DIV" => a / value,
+                    "AND" => a & value,
+                    _ => a | value
+                });
+            }
+            "COMP" => {
+                let value = signed(self.operand(target, immediate, 3)? as u32);
+                self.set_condition_code(signed(self.registers.a).cmp(&value));
+            }
+            "TIX" => {
+                self.registers.x = word(self.registers.x as i64 + 1);
+                let value = signed(self.operand(target, immediate, 3)? as u32);
+                self.set_condition_code(signed(self.registers.x).cmp(&value));
+            }
+            "LDF" => {
+                self.registers.f = float_from_bits(self.operand(target, immediate, 6)?);
+            }
+            "STF" => {
+                let value = float_to_bits(self.registers.f);
+                self.store(target, immediate, 6, value)?;
+            }
+            "ADDF" | "SUBF" | "MULF" | "DIVF" | "COMPF" => {
+                let value = float_from_bits(self.operand(target, immediate, 6)?);
+                let f = self.registers.f;
+                self.registers.f = match name {
+                    "ADDF" => f + value,
+                    "SUBF" => f - value,
+                    "MULF" => f * value,
+                    "DIVF" if value == 0.0 => Err("Division by zero")?,
+                    "DIVF" => f / value,
+                    _ => {
+                        self.set_condition_code(f.partial_cmp(&value).unwrap_or(Ordering::Equal));
+                        f
+                    }
+                };
+            }
+            "J" => {
+                self.registers.pc = target;
+            }
+            "JEQ" | "JGT" | "JLT" => {
+                let expected = match name {
+                    "JEQ" => Ordering::Equal,
+                    "JGT" => Ordering::Greater,
+                    _ => Ordering::Less
+                };
+                if self.condition_code() == expected {
+                    self.registers.pc = target;
+                }
+            }
+            "JSUB" => {
+                self.registers.l = next;
+                self.registers.pc = target;
+            }
+            "RSUB" => {
+                self.registers.pc = self.registers.l;
+            }
+            "TD" => {
+                // every device is always ready
+                let id = self.operand(target, immediate, 1)? as u8;
+                self.device(id);
+                self.set_condition_code(Ordering::Less);
+            }
+            "RD" => {
+                let id = self.operand(target, immediate, 1)? as u8;
+                // an exhausted device keeps reading zero, the usual end of record mark
+                let value = self.device(id).input.pop_front().unwrap_or(0) as u32;
+                self.registers.a = (self.registers.a & 0xFFFF00) | value;
+            }
+            "WD" => {
+                let id = self.operand(target, immediate, 1)? as u8;
+                let value = (self.registers.a & 0xFF) as u8;
+                self.device(id).output.push(value);
+            }
+            _ => {
+                Err(format!("{} is not supported by the simulator", name))?
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn run(self: &mut Self, max_steps: usize) -> Result<usize, Box<dyn Error>> {
+        let start = self.steps;
+
+        while self.step()? {
+            if self.steps - start >= max_steps {
+                Err(format!("Stopped after {} steps at {:06X}", max_steps, self.registers.pc))?
+            }
+        }
+
+        Ok(self.steps - start)
+    }
+}
@@ -0,0 +1,129 @@
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+fn program(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("programs").join(name)
+}
+
+fn sicxe(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_sicxe_assembler")).args(args).output().unwrap()
+}
+
+// a file of its own in the temporary directory for every test
+fn scratch(name: &str, contents: &str) -> String {
+    let path = std::env::temp_dir().join(format!("sicxe-{}-{}", std::process::id(), name));
+    fs::write(&path, contents).unwrap();
+    path.to_string_lossy().into_owned()
+}
+
+#[test]
+fn run_links_control_sections() {
+    let input = scratch("input", "HELLO\0");
+    let output = sicxe(&["run", "--input", &input, program("csect.asm").to_str().unwrap()]);
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("device 05: HELLOEOF"));
+}
+
+#[test]
+fn run_errors_go_to_stderr() {
+    let source = scratch("bad.asm", "PROG    START   0\nBAD     BYTE    X'FF00'\n        END     PROG\n");
+    let output = sicxe(&["run", &source]);
+
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "error: Invalid opcode FF at 000000\n");
+}
+
+#[test]
+fn link_errors_go_to_stderr() {
+    let object = scratch("ext.obj", "HPROG  000000000004\nRNOWHRE\nT000000044B100000\nM00000105+NOWHRE\nE000000\n");
+    let output = sicxe(&["link", &object]);
+
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "error: Undefined external symbol NOWHRE in PROG\n");
+}
+
+#[test]
+fn assembly_errors_exit_with_one() {
+    let source = scratch("wide.asm", "PROG    START   0\n        LDA     #5000\n        END     PROG\n");
+    let output = sicxe(&[&source]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr.contains("error: Value 5000 does not fit in the 12-bit address field"));
+    assert!(stderr.contains("use format 4 (+LDA) or assemble with --relax"));
+}
+
+#[test]
+fn missing_files_exit_with_one() {
+    let missing = std::env::temp_dir().join("sicxe-missing.asm");
+    let missing = missing.to_str().unwrap();
+
+    for args in [vec![missing], vec!["run", missing], vec!["disasm", missing], vec!["check", missing], vec!["link", missing]] {
+        let output = sicxe(&args);
+
+        assert_eq!(output.status.code(), Some(1));
+        assert!(String::from_utf8_lossy(&output.stderr).starts_with(&format!("error: cannot read {}: ", missing)));
+    }
+}
+
+#[test]
+fn commands_without_files_print_usage() {
+    for args in [vec![], vec!["run"], vec!["debug", "--sic"], vec!["link", "--object"], vec!["--listing", "out.lst"]] {
+        let output = sicxe(&args);
+
+        assert_eq!(output.status.code(), Some(1));
+        assert!(String::from_utf8_lossy(&output.stderr).starts_with("Usage: sicxe-assembler [OPTIONS] <FILE>\n"));
+    }
+}
+
+const RESERVED: &str = "PROG    START   1000
+        LDA     #5
+GAP     RESB    3
+SEVEN   WORD    7
+TAIL    RESB    3
+        END     PROG
+";
+
+#[test]
+fn outputs_go_to_the_requested_files() {
+    let source = scratch("files.asm", RESERVED);
+    let (object, listing, symbols) = (scratch("files.obj", ""), scratch("files.lst", ""), scratch("files.sym", ""));
+    let output = sicxe(&[&source, "-o", &object, "--listing", &listing, "--symbols", &symbols]);
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(output.stdout.is_empty());
+    assert_eq!(fs::read_to_string(&object).unwrap(), "HPROG  00100000000C\nT00100003010005\nT00100603000007\nE001000\n");
+    assert!(fs::read_to_string(&listing).unwrap().contains("0004      1006     0        SEVEN         WORD            7 000007"));
+    assert!(fs::read_to_string(&symbols).unwrap().contains("PROG     SEVEN        1006 rel      (default)"));
+}
+
+#[test]
+fn binary_image_keeps_reserved_space_unless_gaps() {
+    let source = scratch("image.asm", RESERVED);
+    let (image, sparse) = (scratch("image.bin", ""), scratch("sparse.bin", ""));
+
+    assert!(sicxe(&[&source, "--binary", &image]).status.success());
+    assert!(sicxe(&[&source, "--binary", &sparse, "--gaps"]).status.success());
+
+    assert_eq!(fs::read(&image).unwrap(), [0x01, 0x00, 0x05, 0, 0, 0, 0x00, 0x00, 0x07, 0, 0, 0]);
+    // the image ends with the last byte of code
+    assert_eq!(fs::read(&sparse).unwrap(), [0x01, 0x00, 0x05, 0, 0, 0, 0x00, 0x00, 0x07]);
+}
+
+#[test]
+fn check_lists_sections_and_fails_on_errors() {
+    let good = scratch("good.obj", "HPROG  000000000003\nT00000003000000\nE000000\n");
+    let output = sicxe(&["check", &good]);
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), format!("{}: PROG   000000 000003 1 text, 0 modification records\n", good));
+
+    let bad = scratch("bad.obj", "HPROG  000000000003\nT00000003000000\nE000009\n");
+    let output = sicxe(&["check", &bad]);
+
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).ends_with("error: 1 error found\n"));
+}
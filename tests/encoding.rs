@@ -0,0 +1,29 @@
+use std::fs;
+use std::process::Command;
+
+// assembles the source with the binary and returns its text records
+fn text_records(name: &str, source: &str, args: &[&str]) -> Vec<String> {
+    let path = std::env::temp_dir().join(format!("sicxe-{}-{}", std::process::id(), name));
+    fs::write(&path, source).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_sicxe_assembler")).args(args).arg(&path).output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    String::from_utf8_lossy(&output.stdout).lines().filter(|line| line.starts_with('T')).map(String::from).collect()
+}
+
+#[test]
+fn format_1_takes_one_byte() {
+    let records = text_records("fix.asm", "PROG    START   0\n        FIX\n        NORM\n        END     PROG\n", &[]);
+
+    assert_eq!(records, ["T00000002C4C8"]);
+}
+
+#[test]
+fn rsub_is_format_3() {
+    let records = text_records("rsub.asm", "PROG    START   0\n        FIX\n        RSUB\n        END     PROG\n", &[]);
+    assert_eq!(records, ["T00000004C44F0000"]);
+
+    let records = text_records("rsub-sic.asm", "PROG    START   0\n        RSUB\n        END     PROG\n", &["--sic"]);
+    assert_eq!(records, ["T000000034C0000"]);
+}
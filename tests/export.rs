@@ -0,0 +1,74 @@
+use sicxe_assembler::{assemble, intel_hex, s_records, LoadedProgram, Options};
+
+#[test]
+fn image_checksums() {
+    let program = assemble("PROG    START   1000\nFIRST   LDA     #5\n        RSUB\nVAL     WORD    7\n        END     FIRST\n", &Options::default()).unwrap();
+    let linked = LoadedProgram::link(&program.object_programs(), 0x1000).unwrap();
+
+    assert_eq!(intel_hex(&linked), [
+        ":091000000100054F00000000078B",
+        ":0400000500001000E7",
+        ":00000001FF"
+    ]);
+    assert_eq!(s_records(&linked), [
+        "S007000050524F47C0",
+        "S10C10000100054F000000000787",
+        "S5030001FB",
+        "S9031000EC"
+    ]);
+}
+
+#[test]
+fn json() {
+    let program = assemble(". copies nothing\nPROG    START   0\n\nFIRST   LDA     #5      load five\n        RSUB\n        END     FIRST\n", &Options::default()).unwrap();
+
+    assert_eq!(program.json(), r#"{
+  "sections": [
+    {
+      "name": "PROG",
+      "address": 0,
+      "length": 6,
+      "entry": 0,
+      "blocks": [
+        {"name": "", "address": 0, "length": 6}
+      ],
+      "defines": [],
+      "references": [],
+      "texts": [
+        {"address": 0, "length": 6, "bytes": "0100054F0000"}
+      ],
+      "modifications": [],
+      "symbols": [
+        {"name": "FIRST", "value": 0, "kind": "relative", "block": ""},
+        {"name": "PROG", "value": 0, "kind": "relative", "block": ""}
+      ]
+    }
+  ],
+  "listing": [
+    {"line": 1, "kind": "comment", "text": ". copies nothing", "address": null, "block": null, "length": 0, "bytes": ""},
+    {"line": 2, "kind": "source", "text": "PROG    START   0", "address": 0, "block": 0, "length": 0, "bytes": ""},
+    {"line": 3, "kind": "comment", "text": "", "address": null, "block": null, "length": 0, "bytes": ""},
+    {"line": 4, "kind": "source", "text": "FIRST   LDA     #5      load five", "address": 0, "block": 0, "length": 3, "bytes": "010005"},
+    {"line": 5, "kind": "source", "text": "        RSUB", "address": 3, "block": 0, "length": 3, "bytes": "4F0000"},
+    {"line": 6, "kind": "source", "text": "        END     FIRST", "address": 6, "block": 0, "length": 0, "bytes": ""}
+  ]
+}
+"#);
+}
+
+#[test]
+fn s_record_address_width() {
+    let program = |address, entry| LoadedProgram{name: String::from("PROG"), address, bytes: vec![1, 2, 3], loaded: vec![true; 3], entry: Some(entry), estab: vec![]};
+
+    // the last byte at FFFF still fits in 16 bits
+    let records = s_records(&program(0xFFFD, 0xFFFD));
+    assert_eq!(records[1..], ["S106FFFD010203F7", "S5030001FB", "S903FFFD00"]);
+
+    // an entry point at 10000 does not
+    let records = s_records(&program(0x10000, 0x10000));
+    assert_eq!(records[1..], ["S207010000010203F1", "S5030001FB", "S804010000FA"]);
+
+    // and a last byte at FFFFFF still fits in 24 bits
+    let records = s_records(&program(0xFFFFFD, 0xFFFFFD));
+    assert_eq!(records[1..], ["S207FFFFFD010203F7", "S5030001FB", "S804FFFFFD00"]);
+}
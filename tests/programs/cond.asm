@@ -0,0 +1,26 @@
+&DEBUG SET 0
+&N SET 0
+COPY START 1000
+DUMP MACRO &DEV,&EOR=
+IF (&EOR NE '')
+LDCH =X'&EOR'
+ELSE
+CLEAR A
+ENDIF
+WD =X'&DEV'
+MEND
+FIRST STL RETADR
+IF (&DEBUG EQ 1)
+DUMP 05,EOR=04
+ELSE
+DUMP 06
+ENDIF
+WHILE (&N LT 3)
+LDA #&N
+&N SET &N+1
+ENDW
+IF (NOT &DEBUG)
+LDA #99
+ENDIF
+RETADR RESW 1
+END FIRST
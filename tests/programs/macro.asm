@@ -0,0 +1,25 @@
+COPY START 1000
+RDBUFF MACRO &INDEV,&BUFADR,&RECLTH,&EOR=04
+CLEAR X
+CLEAR A
+CLEAR S
++LDT #4096
+$LOOP TD =X'&INDEV'
+JEQ $LOOP
+RD =X'&INDEV'
+COMPR A,S
+JEQ $EXIT
+STCH &BUFADR,X
+TIXR T
+JLT $LOOP
+$EXIT STX &RECLTH
+MEND
+FIRST STL RETADR
+CLOOP RDBUFF F1,BUFFER,LENGTH
+RDBUFF F3,BUF2,LENGTH,EOR=05
+J CLOOP
+RETADR RESW 1
+LENGTH RESW 1
+BUFFER RESB 4096
+BUF2 RESB 10
+END FIRST
@@ -0,0 +1,223 @@
+use sicxe_assembler::{assemble, disassemble, AssembledProgram, ListingEntry, LoadedProgram, Mode, ObjectProgram, Options, Simulator, SymbolEntry, SymbolKind};
+
+fn assembled(source: &str, options: Options) -> AssembledProgram {
+    match assemble(source, &options) {
+        Ok(program) => program,
+        Err(diagnostics) => {
+            let rendered: Vec<String> = diagnostics.iter().map(|diagnostic| diagnostic.render("test.asm")).collect();
+            panic!("{}", rendered.join("\n"))
+        }
+    }
+}
+
+fn lines(text: &str) -> Vec<&str> {
+    text.lines().collect()
+}
+
+// figure 2.5 of the textbook, its object program is figure 2.8
+#[test]
+fn copy_sicxe() {
+    let program = assembled(include_str!("programs/copy.asm"), Options::default());
+
+    assert_eq!(lines(&program.records()), [
+        "HCOPY  000000001077",
+        "T0000001D17202D69202D4B1010360320262900003320074B10105D3F2FEC032010",
+        "T00001D130F20160100030F200D4B10105D3E2003454F46",
+        "T0010361DB410B400B44075101000E32019332FFADB2013A00433200857C003B850",
+        "T0010531D3B2FEA1340004F0000F1B410774000E32011332FFA53C003DF2008B850",
+        "T001070073B2FEF4F000005",
+        "M00000705",
+        "M00001405",
+        "M00002705",
+        "E000000"
+    ]);
+}
+
+// figure 2.1, the same program for the standard SIC machine
+#[test]
+fn copy_sic() {
+    let options = Options{mode: Mode::SIC, ..Options::default()};
+    let program = assembled(include_str!("programs/sic.asm"), options);
+    let records = program.records();
+
+    assert_eq!(lines(&records)[..6], [
+        "HCOPY  00100000107A",
+        "T0010001B1410334820390010362810303010154820613C100300102A0C1039",
+        "T00101B1800102D0C10364820610810334C0000454F46000003000000",
+        "T0020391B041030001030E0205D30203FD8205D2810303020575490392C205E",
+        "T0020541C38203F1010364C0000F1001000041030E02079302064509039DC2079",
+        "T0020700A2C10363820644C000005"
+    ]);
+    // every instruction holds a direct address that moves with the program
+    assert_eq!(records.lines().filter(|line| line.starts_with('M')).count(), 31);
+    assert!(records.lines().all(|line| !line.starts_with('M') || line.ends_with("04")));
+    assert_eq!(records.lines().last(), Some("E001000"));
+}
+
+// figure 2.15, its object program is figure 2.17
+#[test]
+fn control_sections() {
+    let program = assembled(include_str!("programs/csect.asm"), Options::default());
+
+    assert_eq!(lines(&program.records()), [
+        "HCOPY  000000001033",
+        "DBUFFER000033BUFEND001033LENGTH00002D",
+        "RRDREC WRREC ",
+        "T0000001D1720274B1000000320232900003320074B1000003F2FEC0320160F2016",
+        "T00001D0D0100030F200A4B1000003E2000",
+        "T00003003454F46",
+        "M00000405+RDREC",
+        "M00001105+WRREC",
+        "M00002405+WRREC",
+        "E000000",
+        "HRDREC 00000000002B",
+        "RBUFFERLENGTHBUFEND",
+        "T0000001DB410B400B44077201FE3201B332FFADB2015A00433200957900000B850",
+        "T00001D0E3B2FE9131000004F0000F1001000",
+        "M00001805+BUFFER",
+        "M00002105+LENGTH",
+        "E",
+        "HWRREC 00000000001C",
+        "RLENGTHBUFFER",
+        "T0000001CB41077100000E32012332FFA53900000DF2008B8503B2FEE4F000005",
+        "M00000305+LENGTH",
+        "M00000D05+BUFFER",
+        "E"
+    ]);
+}
+
+#[test]
+fn symbols_and_listing_lines() {
+    let program = assembled(include_str!("programs/csect.asm"), Options::default());
+
+    let symbols = program.symbols();
+    assert_eq!(symbols[0], SymbolEntry{section: "COPY", name: "BUFEND", value: 0x1033, kind: SymbolKind::Relative, block: Some("")});
+    assert!(symbols.contains(&SymbolEntry{section: "RDREC", name: "BUFFER", value: 0, kind: SymbolKind::External, block: None}));
+
+    let listing = program.listing_lines();
+    assert_eq!(listing[2], ListingEntry{line: 3, text: "         EXTREF  RDREC,WRREC", address: Some(0), block: Some(0), bytes: vec![]});
+    assert_eq!(listing[4], ListingEntry{line: 5, text: "CLOOP    +JSUB   RDREC", address: Some(3), block: Some(0), bytes: vec![0x4B, 0x10, 0x00, 0x00]});
+}
+
+#[test]
+fn link_control_sections() {
+    let program = assembled(include_str!("programs/csect.asm"), Options::default());
+    let linked = LoadedProgram::link(&program.object_programs(), 0x4000).unwrap();
+
+    let addresses: Vec<(Option<&str>, u64)> = linked.estab.iter().map(|entry| (entry.symbol.as_deref(), entry.address)).collect();
+    assert_eq!(addresses, [
+        (None, 0x4000), (Some("BUFFER"), 0x4033), (Some("BUFEND"), 0x5033), (Some("LENGTH"), 0x402D),
+        (None, 0x5033),
+        (None, 0x505E)
+    ]);
+
+    // +JSUB RDREC and +JSUB WRREC now hold the addresses of the sections
+    assert_eq!(linked.bytes[0x03..0x07], [0x4B, 0x10, 0x50, 0x33]);
+    assert_eq!(linked.bytes[0x10..0x14], [0x4B, 0x10, 0x50, 0x5E]);
+    // +STCH BUFFER,X in RDREC keeps its x bit
+    assert_eq!(linked.bytes[0x33 + 0x1000 + 0x17..0x33 + 0x1000 + 0x1B], [0x57, 0x90, 0x40, 0x33]);
+    assert_eq!(linked.entry, Some(0x4000));
+}
+
+#[test]
+fn link_rejects_undefined_externals() {
+    let program = assembled("PROG    START   0\n        EXTREF  NOWHRE\n        +JSUB   NOWHRE\n        END     PROG\n", Options::default());
+
+    let err = LoadedProgram::link(&program.object_programs(), 0).err().unwrap();
+    assert_eq!(err.to_string(), "Undefined external symbol NOWHRE in PROG");
+}
+
+// figure 4.1 with the literals of the expansion out of reach, so that --relax is needed
+#[test]
+fn macro_expansion() {
+    let source = include_str!("programs/macro.asm");
+    assert!(assemble(source, &Options::default()).is_err());
+
+    let program = assembled(source, Options{relax: true, ..Options::default()});
+    assert_eq!(lines(&program.records()), [
+        "HCOPY  001000001063",
+        "T0010001D17204EB410B400B44075101000E3102061332FF9DB102061A004332008",
+        "T00101D1C57A037B8503B2FE813202CB410B400B44075101000E3102062332FF9",
+        "T00103918DB102062A00433200957902057B8503B2FE71320063F2FB2",
+        "T00206102F1F3",
+        "M00100E05",
+        "M00101505",
+        "M00103305",
+        "M00103A05",
+        "M00104305",
+        "E001000"
+    ]);
+
+    // the labels of every expansion are unique
+    let listing = program.listing();
+    assert!(listing.contains("$AALOOP") && listing.contains("$ABLOOP"));
+}
+
+#[test]
+fn conditional_expansion() {
+    let program = assembled(include_str!("programs/cond.asm"), Options::default());
+
+    assert_eq!(lines(&program.records()), [
+        "HCOPY  001000000018",
+        "T00100014172011B400DF200F010000010001010002010063",
+        "T0010170106",
+        "E001000"
+    ]);
+}
+
+// the records read back from their text are the ones the assembler hands to the loader
+#[test]
+fn object_round_trip() {
+    let program = assembled(include_str!("programs/csect.asm"), Options::default());
+    let read = ObjectProgram::read(&program.records()).unwrap();
+    let assembled = program.object_programs();
+
+    assert_eq!(read.len(), assembled.len());
+    for (read, assembled) in read.iter().zip(&assembled) {
+        assert_eq!(read.header.program_name, assembled.header.program_name);
+        assert_eq!(read.defines, assembled.defines);
+        assert_eq!(read.references, assembled.references);
+        assert_eq!(read.start_address, assembled.start_address);
+        let texts = |program: &ObjectProgram| program.texts.iter().map(|text| (text.address, text.bytes.clone())).collect::<Vec<_>>();
+        assert_eq!(texts(read), texts(assembled));
+    }
+}
+
+#[test]
+fn disassemble_copy() {
+    let program = assembled(include_str!("programs/copy.asm"), Options::default());
+    let read = ObjectProgram::read(&program.records()).unwrap();
+    let lines = disassemble(&read);
+    let instructions: Vec<String> = lines.iter()
+        .filter_map(|line| line.split(" . ").next())
+        .map(|code| code.split_whitespace().collect::<Vec<_>>().join(" "))
+        .collect();
+
+    assert_eq!(instructions[0], "COPY START 0");
+    assert!(instructions.contains(&String::from("L000006 +JSUB L001036")));
+    assert!(instructions.contains(&String::from("J @L000030")));
+    assert!(instructions.contains(&String::from("COMPR A,S")));
+    assert!(instructions.contains(&String::from("+LDT #4096")));
+    assert_eq!(instructions.last().map(String::as_str), Some("END L000000"));
+}
+
+#[test]
+fn disassemble_nothing() {
+    assert!(disassemble(&[]).is_empty());
+}
+
+// COPY copies one record from device F1 to device 05, then writes EOF
+#[test]
+fn run_copy() {
+    for source in [include_str!("programs/copy.asm"), include_str!("programs/csect.asm")] {
+        let program = assembled(source, Options::default());
+        let mut simulator = Simulator::new();
+        simulator.load(&program).unwrap();
+        simulator.device(0xF1).input.extend(b"HELLO\0");
+
+        simulator.run(100_000).unwrap();
+
+        assert!(simulator.halted);
+        assert_eq!(simulator.devices[&0x05].output, b"HELLOEOF");
+    }
+}